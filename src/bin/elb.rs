@@ -0,0 +1,44 @@
+//
+// This file is part of elb-dev-tools-ng
+//
+// Copyright (C) 2020 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+
+use anyhow::Result;
+use elb_dev_tools_ng::{
+    exit_on_error, generate_completions, generate_man_page, kemenn, nevez,
+    parse_args, zrdm,
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "elb", about = "Development tools")]
+enum ElbOpts {
+    #[structopt(name = "announce", about = "Announce a project release")]
+    Announce(kemenn::KemennOpts),
+
+    #[structopt(name = "changelog", about = "Generate a changelog")]
+    Changelog(nevez::NevezOptions),
+
+    #[structopt(name = "readme", about = "Display README from tarball")]
+    Readme(zrdm::ZrdmOpts),
+}
+
+fn try_main() -> Result<()> {
+    if generate_completions::<ElbOpts>("elb")?
+        || generate_man_page::<ElbOpts>("elb")?
+    {
+        return Ok(());
+    }
+    match parse_args()? {
+        ElbOpts::Announce(opts) => kemenn::run(opts),
+        ElbOpts::Changelog(opts) => nevez::run(opts),
+        ElbOpts::Readme(opts) => zrdm::run(opts),
+    }
+}
+
+fn main() {
+    exit_on_error(try_main())
+}
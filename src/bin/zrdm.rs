@@ -0,0 +1,26 @@
+//
+// This file is part of elb-dev-tools-ng
+//
+// Copyright (C) 2020 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+
+use anyhow::Result;
+use elb_dev_tools_ng::zrdm::{self, ZrdmOpts};
+use elb_dev_tools_ng::{
+    exit_on_error, generate_completions, generate_man_page, parse_args,
+};
+
+fn try_main() -> Result<()> {
+    if generate_completions::<ZrdmOpts>("zrdm")?
+        || generate_man_page::<ZrdmOpts>("zrdm")?
+    {
+        return Ok(());
+    }
+    zrdm::run(parse_args()?)
+}
+
+fn main() {
+    exit_on_error(try_main())
+}
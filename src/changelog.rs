@@ -0,0 +1,154 @@
+//
+// This file is part of elb-dev-tools-ng
+//
+// Copyright (C) 2020 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+
+//! Changelog headings shared by the tools
+
+use crate::ErrorKind;
+use anyhow::Result;
+use regex::Regex;
+
+/// Pattern of the date of a heading
+const DATE_PATTERN: &str = r"[\d]{4}-[\d]{2}-[\d]{2}";
+
+/// Format of the version headings of a changelog
+#[derive(Debug, Clone)]
+pub struct HeadingFormat {
+    heading: Regex,
+    template: Option<String>,
+}
+
+impl HeadingFormat {
+    /// Create a format from a preset name, a template or a custom regex
+    ///
+    /// Presets are `keepachangelog`, `paren` and `rst`. A template contains
+    /// `{version}` and optionally `{date}`, like `## [{version}] - {date}`.
+    /// Any other format is a regex capturing the version, which can only be
+    /// used to find headings.
+    pub fn new(format: &str) -> Result<Self> {
+        let (pattern, template) = match format {
+            "keepachangelog" => (
+                format!(r"^##\s+\[([^\]]+)\](?:\s+-\s+{})?\s*$", DATE_PATTERN),
+                Some("## [{version}] - {date}"),
+            ),
+            "paren" => (
+                r"^##\s+(\S+)\s+\(.*\)\s*$".to_string(),
+                Some("## {version} ({date})"),
+            ),
+            "rst" => {
+                (r"^v?([\d]+\.[\d]+\S*)(?:\s+\(.*\))?\s*$".to_string(), None)
+            }
+            template if template.contains("{version}") => {
+                (template_pattern(template), Some(template))
+            }
+            custom => (custom.to_string(), None),
+        };
+        let heading = Regex::new(&pattern).map_err(|e| {
+            ErrorKind::Input.error(format!("Invalid heading format ({})", e))
+        })?;
+        if heading.captures_len() < 2 {
+            return Err(ErrorKind::Input
+                .error("Heading format must capture the version"));
+        }
+        Ok(HeadingFormat {
+            heading,
+            template: template.map(String::from),
+        })
+    }
+
+    /// Check if a line is a version heading
+    pub fn is_heading(&self, line: &str) -> bool {
+        self.heading.is_match(line)
+    }
+
+    /// Check if a line is the heading of a release, not of unreleased changes
+    pub fn is_release(&self, line: &str) -> bool {
+        self.version(line)
+            .is_some_and(|version| !version.eq_ignore_ascii_case("unreleased"))
+    }
+
+    /// Return the version of a heading line
+    pub fn version<'a>(&self, line: &'a str) -> Option<&'a str> {
+        self.heading
+            .captures(line)
+            .and_then(|caps| caps.get(1))
+            .map(|m| m.as_str())
+    }
+
+    /// Check if headings can be generated, not only found
+    pub fn can_generate(&self) -> bool {
+        self.template.is_some()
+    }
+
+    /// Return the heading of `version`, released on `date`
+    pub fn heading(&self, version: &str, date: &str) -> Result<String> {
+        let template = self.template.as_ref().ok_or_else(|| {
+            ErrorKind::Input.error("Heading format can not generate headings")
+        })?;
+        Ok(template
+            .replace("{version}", version)
+            .replace("{date}", date))
+    }
+}
+
+impl Default for HeadingFormat {
+    fn default() -> Self {
+        HeadingFormat::new("keepachangelog").unwrap()
+    }
+}
+
+/// Return the regex matching the headings produced by `template`
+fn template_pattern(template: &str) -> String {
+    let parts: Vec<String> = template
+        .split("{version}")
+        .map(|part| {
+            part.split("{date}")
+                .map(regex::escape)
+                .collect::<Vec<String>>()
+                .join(DATE_PATTERN)
+        })
+        .collect();
+    format!(r"^{}\s*$", parts.join("(.+?)"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_find_versions() {
+        let format = HeadingFormat::new("keepachangelog").unwrap();
+        assert_eq!(format.version("## [v1.0.0] - 2020-01-01"), Some("v1.0.0"));
+        assert_eq!(format.version("## [Unreleased]"), Some("Unreleased"));
+        assert!(!format.is_release("## [Unreleased]"));
+        assert!(format.is_release("## [v1.0.0] - 2020-01-01"));
+        assert_eq!(format.version("# News"), None);
+        let format = HeadingFormat::new("paren").unwrap();
+        assert_eq!(format.version("## 1.0.0 (2020-01-01)"), Some("1.0.0"));
+        let format = HeadingFormat::new("rst").unwrap();
+        assert_eq!(format.version("v1.0.0 (2020-01-01)"), Some("1.0.0"));
+        assert!(!format.can_generate());
+        assert!(format.heading("1.0.0", "2020-01-01").is_err());
+    }
+
+    #[test]
+    fn template_finds_and_generates_headings() {
+        let format =
+            HeadingFormat::new("### Release {version} ({date})").unwrap();
+        let heading = format.heading("1.2.0", "2020-02-01").unwrap();
+        assert_eq!(heading, "### Release 1.2.0 (2020-02-01)");
+        assert_eq!(format.version(&heading), Some("1.2.0"));
+        assert!(!format.is_heading("### Release 1.2.0 (soon)"));
+    }
+
+    #[test]
+    fn custom_regex_must_capture_version() {
+        assert!(HeadingFormat::new(r"^== (\S+) ==$").is_ok());
+        assert!(HeadingFormat::new(r"^== \S+ ==$").is_err());
+        assert!(HeadingFormat::new(r"^== (\S+ ==$").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+//
+// This file is part of elb-dev-tools-ng
+//
+// Copyright (C) 2020 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+
+//! Git operations shared by the tools
+
+use crate::{git_command, run_command_in, shell_quote, ErrorKind};
+use anyhow::Result;
+use std::path::Path;
+use std::process::{Child, Stdio};
+
+/// Options of the git commands
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// Print each command on stderr before running it
+    pub show_commands: bool,
+    /// Fail on output that is not valid UTF-8, instead of replacing invalid
+    /// sequences
+    pub strict_utf8: bool,
+}
+
+/// Return the shell command line running git with `args` in `repo`
+fn command_line(repo: &Path, args: &[&str]) -> String {
+    let repo = repo.to_string_lossy();
+    let mut line = format!("git -C {}", shell_quote(&repo));
+    for arg in args {
+        line.push(' ');
+        line.push_str(&shell_quote(arg));
+    }
+    line
+}
+
+/// Run git with `args` in repository `repo`
+pub fn run<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    args: &[&str],
+    error: &str,
+) -> Result<String> {
+    trace(repo.as_ref(), options, args);
+    let mut cmd = git_command();
+    cmd.args(args);
+    run_command_in(&mut cmd, repo, error, options.strict_utf8)
+}
+
+/// Print the command line running git with `args`, if asked to
+fn trace(repo: &Path, options: &Options, args: &[&str]) {
+    if options.show_commands {
+        eprintln!("+ {}", command_line(repo, args));
+    }
+}
+
+/// Find the latest tag, lightweight ones included if `all` is set
+///
+/// If `pattern` is given, only tags matching this glob are considered.
+pub fn latest_tag<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    all: bool,
+    pattern: Option<&str>,
+) -> Result<String> {
+    describe(repo, options, None, all, pattern)
+}
+
+/// Find the latest tag before `tag`, like `latest_tag`
+///
+/// Return `None` if no tag comes before `tag`.
+pub fn previous_tag<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    tag: &str,
+    all: bool,
+    pattern: Option<&str>,
+) -> Result<Option<String>> {
+    let repo = repo.as_ref();
+    let commit = run(
+        repo,
+        options,
+        &["rev-list", "--parents", "-n", "1", tag],
+        "git-rev-list failed",
+    )?;
+    if commit.split_whitespace().count() < 2 {
+        return Ok(None);
+    }
+    let parent = format!("{}^", tag);
+    let merged = format!("--merged={}", parent);
+    let refs = match pattern {
+        Some(pattern) => format!("refs/tags/{}", pattern),
+        None => "refs/tags".to_string(),
+    };
+    let types = run(
+        repo,
+        options,
+        &["for-each-ref", &merged, "--format=%(objecttype)", &refs],
+        "git-for-each-ref failed",
+    )?;
+    if !types.lines().any(|kind| all || kind == "tag") {
+        return Ok(None);
+    }
+    describe(repo, options, Some(&parent), all, pattern).map(Some)
+}
+
+/// Check if `tag` exists
+pub fn tag_exists<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    tag: &str,
+) -> Result<bool> {
+    let rev = format!("refs/tags/{}", tag);
+    let args = ["rev-parse", "--verify", "--quiet", &rev];
+    trace(repo.as_ref(), options, &args);
+    let output = git_command()
+        .args(args)
+        .current_dir(repo)
+        .output()
+        .map_err(|e| {
+            ErrorKind::Command.error(format!("git-rev-parse failed ({})", e))
+        })?;
+    Ok(output.status.success())
+}
+
+/// Find the latest tag reachable from `rev`, or from HEAD if not given
+fn describe<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    rev: Option<&str>,
+    all: bool,
+    pattern: Option<&str>,
+) -> Result<String> {
+    let mut args = vec!["describe", "--abbrev=0"];
+    if all {
+        args.push("--tags");
+    }
+    if let Some(pattern) = pattern {
+        args.push("--match");
+        args.push(pattern);
+    }
+    if let Some(rev) = rev {
+        args.push(rev);
+    }
+    run(repo, options, &args, "git-describe failed")
+}
+
+/// Return the URL of `remote`
+pub fn remote_url<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    remote: &str,
+) -> Result<String> {
+    let key = format!("remote.{}.url", remote);
+    let error = format!("No URL found for remote {}", remote);
+    run(repo, options, &["config", "--get", &key], &error)
+}
+
+/// Options of `log`
+#[derive(Debug, Default, Clone)]
+pub struct LogOptions {
+    /// Pretty format of the commits
+    pub format: Option<String>,
+    /// Skip merge commits
+    pub no_merges: bool,
+    /// Skip commits whose message matches this pattern
+    pub exclude_grep: Option<String>,
+    /// Only show commits more recent than this date
+    pub since: Option<String>,
+    /// Only follow the first parent of merge commits
+    pub first_parent: bool,
+    /// Skip commits only touching paths matching these patterns
+    pub exclude_paths: Vec<String>,
+}
+
+impl LogOptions {
+    /// Return the arguments of git-log for `range`
+    fn args(&self, range: &str) -> Vec<String> {
+        let mut args = vec!["log".to_string()];
+        if let Some(format) = self.format.as_ref() {
+            args.push(format!("--format={}", format));
+        }
+        if self.no_merges {
+            args.push("--no-merges".to_string());
+        }
+        if self.first_parent {
+            args.push("--first-parent".to_string());
+        }
+        if let Some(pattern) = self.exclude_grep.as_ref() {
+            args.push("--invert-grep".to_string());
+            args.push("--grep".to_string());
+            args.push(pattern.clone());
+        }
+        if let Some(date) = self.since.as_ref() {
+            args.push(format!("--since={}", date));
+        }
+        args.push(range.to_string());
+        if !self.exclude_paths.is_empty() {
+            args.push("--".to_string());
+            for path in &self.exclude_paths {
+                args.push(format!(":(exclude){}", path));
+            }
+        }
+        args
+    }
+}
+
+/// Return the log of the commits of `range`
+pub fn log<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    range: &str,
+    log_options: &LogOptions,
+) -> Result<String> {
+    let args = log_options.args(range);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    run(repo, options, &args, "git-log failed")
+}
+
+/// Start git-log for the commits of `range`, with its output piped
+///
+/// This allows reading the log as it is produced, for long histories.
+pub fn spawn_log<P: AsRef<Path>>(
+    repo: P,
+    options: &Options,
+    range: &str,
+    log_options: &LogOptions,
+) -> Result<Child> {
+    let args = log_options.args(range);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    trace(repo.as_ref(), options, &args);
+    git_command()
+        .args(&args)
+        .current_dir(repo)
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            ErrorKind::Command.error(format!("git-log failed ({})", e))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_quotes_args() {
+        assert_eq!(
+            command_line(
+                Path::new("/src/my repo"),
+                &["log", "--format=%H %s", "--grep", "it's", "v1.0..HEAD"]
+            ),
+            "git -C '/src/my repo' log '--format=%H %s' --grep 'it'\\''s' \
+             v1.0..HEAD"
+        );
+    }
+
+    #[test]
+    fn log_options_build_args() {
+        let options = LogOptions {
+            format: Some("%H".to_string()),
+            no_merges: true,
+            exclude_grep: Some("^Squash".to_string()),
+            since: Some("2020-01-01".to_string()),
+            first_parent: true,
+            exclude_paths: vec!["docs".to_string(), "*.md".to_string()],
+        };
+        assert_eq!(
+            options.args("v1.0..HEAD"),
+            vec![
+                "log",
+                "--format=%H",
+                "--no-merges",
+                "--first-parent",
+                "--invert-grep",
+                "--grep",
+                "^Squash",
+                "--since=2020-01-01",
+                "v1.0..HEAD",
+                "--",
+                ":(exclude)docs",
+                ":(exclude)*.md"
+            ]
+        );
+        assert_eq!(LogOptions::default().args("HEAD"), vec!["log", "HEAD"]);
+    }
+}
@@ -6,33 +6,45 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 //
 
+//! Announce a project release
+
+use crate::changelog::HeadingFormat;
+use crate::{git, open_output, wrap_text, ErrorKind};
 use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
 use dirs;
-use elb_dev_tools_ng::run_command_or;
 use handlebars::{no_escape, Handlebars};
 use regex::Regex;
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::str;
 use structopt::StructOpt;
 
 const DEFAULT_TEMPLATE: &str = r"From: {{emitter}}
 To: {{recipients}}
+{{#if cc}}Cc: {{cc}}
+{{/if}}Bcc: {{emitter}}
 Subject: [{{prefix}}] {{project}} {{version}} is available
-bcc: {{emitter}}
-
+Message-ID: {{message_id}}
+{{#if in_reply_to}}In-Reply-To: {{in_reply_to}}
+References: {{references}}
+{{/if}}
 Hi!
 
 Version {{version}} of {{project}} is available in its repository [1].
 
 [1] {{url}}
 
-{{text}}
+{{#if highlights}}{{highlights}}
+
+{{/if}}{{text}}
 
 Regards,
 
@@ -41,7 +53,7 @@ Regards,
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "kemenn", about = "Announce a project release")]
-struct KemennOpts {
+pub struct KemennOpts {
     #[structopt(
         short = "f",
         long = "from",
@@ -50,6 +62,13 @@ struct KemennOpts {
     )]
     emitter: Option<String>,
 
+    #[structopt(
+        long = "name",
+        help = "Emitter display name",
+        value_name = "NAME"
+    )]
+    name: Option<String>,
+
     #[structopt(
         short = "c",
         long = "changelog",
@@ -59,6 +78,16 @@ struct KemennOpts {
     )]
     changelog: Option<PathBuf>,
 
+    #[structopt(
+        long = "changelog-format",
+        alias = "heading-format",
+        help = "Changelog headings: keepachangelog, paren, rst, a template \
+                with {version} and {date}, or a regex",
+        default_value = "keepachangelog",
+        value_name = "FORMAT"
+    )]
+    changelog_format: String,
+
     #[structopt(
         short = "t",
         long = "template",
@@ -67,6 +96,20 @@ struct KemennOpts {
     )]
     template: Option<PathBuf>,
 
+    #[structopt(
+        long = "strict-template",
+        help = "Fail on unknown template variables"
+    )]
+    strict_template: bool,
+
+    #[structopt(
+        long = "partials-dir",
+        help = "Directory of template partials, named after their file",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    partials_dir: Option<PathBuf>,
+
     #[structopt(
         short = "i",
         long = "input",
@@ -76,6 +119,41 @@ struct KemennOpts {
     )]
     input: Option<PathBuf>,
 
+    #[structopt(
+        short = "g",
+        long = "group",
+        help = "Recipient group to select from input",
+        number_of_values = 1,
+        value_name = "NAME"
+    )]
+    groups: Vec<String>,
+
+    #[structopt(
+        long = "list-vars",
+        help = "List template variables instead of rendering"
+    )]
+    list_vars: bool,
+
+    #[structopt(
+        long = "plain-changelog",
+        help = "Insert changelog as plain text"
+    )]
+    plain_changelog: bool,
+
+    #[structopt(
+        long = "highlights",
+        help = "Path to Markdown file of release highlights",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    highlights: Option<PathBuf>,
+
+    #[structopt(
+        long = "check",
+        help = "Only check that release information resolves"
+    )]
+    check: bool,
+
     #[structopt(
         short = "l",
         long = "loose",
@@ -86,7 +164,7 @@ struct KemennOpts {
     #[structopt(
         short = "o",
         long = "output",
-        help = "Path to output file",
+        help = "Path to output file, - for stdout",
         value_name = "PATH",
         parse(from_os_str)
     )]
@@ -95,12 +173,72 @@ struct KemennOpts {
     #[structopt(
         short = "P",
         long = "parameter",
-        help = "Extra K:V key value pair",
+        help = "Extra K:V key value pair, overriding all other values",
         number_of_values = 1,
         value_name = "STRING"
     )]
     parameters: Option<Vec<String>>,
 
+    #[structopt(
+        long = "parameters-file",
+        help = "Path to file of extra K:V key value pairs, overriding \
+                built-in values",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    parameters_file: Option<PathBuf>,
+
+    #[structopt(
+        short = "w",
+        long = "wrap",
+        help = "Wrap mail body to width",
+        value_name = "COLUMNS"
+    )]
+    wrap: Option<usize>,
+
+    #[structopt(
+        long = "no-signature",
+        help = "Do not insert signature from ~/.signature"
+    )]
+    no_signature: bool,
+
+    #[structopt(long = "crlf", help = "Use CRLF line endings")]
+    crlf: bool,
+
+    #[structopt(
+        long = "show-commands",
+        help = "Print git commands before running them"
+    )]
+    show_commands: bool,
+
+    #[structopt(
+        long = "strict-utf8",
+        help = "Fail on git output that is not valid UTF-8"
+    )]
+    strict_utf8: bool,
+
+    #[structopt(
+        long = "in-reply-to",
+        help = "Message-ID of the mail to reply to",
+        value_name = "MESSAGE-ID"
+    )]
+    in_reply_to: Option<String>,
+
+    #[structopt(
+        long = "cc-contributors",
+        help = "Copy authors of the release, except those opted out"
+    )]
+    cc_contributors: bool,
+
+    #[structopt(
+        short = "r",
+        long = "remote",
+        help = "Remote to get repository URL from",
+        default_value = "origin",
+        value_name = "NAME"
+    )]
+    remote: String,
+
     #[structopt(
         short = "R",
         long = "release",
@@ -109,6 +247,14 @@ struct KemennOpts {
     )]
     release: Option<String>,
 
+    #[structopt(
+        long = "tarball",
+        help = "Release tarball to give size and SHA256 of",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    tarball: Option<PathBuf>,
+
     #[structopt(help = "Repository")]
     repository: PathBuf,
 
@@ -125,47 +271,29 @@ struct ReleaseInfo {
     changelog: String,
 }
 
-fn get_repo_url<P: AsRef<Path>>(path: P) -> Result<String> {
-    let mut cmd = Command::new("git");
-    cmd.arg("--git-dir")
-        .arg(path.as_ref())
-        .arg("config")
-        .arg("--get")
-        .arg("remote.origin.url");
-
-    run_command_or(&mut cmd, "git config failed")
-}
-
-fn get_repo_latest_version<P: AsRef<Path>>(path: P) -> Result<String> {
-    let mut cmd = Command::new("git");
-    cmd.arg("--git-dir")
-        .arg(path.as_ref())
-        .arg("describe")
-        .arg("--abbrev=0")
-        .arg("--tags");
-
-    run_command_or(&mut cmd, "git describe failed")
+fn is_heading_underline(line: &str) -> bool {
+    !line.is_empty() && line.chars().all(|c| "=-~^".contains(c))
 }
 
 fn get_repo_changelog<P: AsRef<Path>>(
     path: P,
     version: &str,
+    format: &HeadingFormat,
 ) -> Result<String> {
-    let pattern =
-        format!(r"^##\s+\[{}]\s+-\s+[\d]{{4}}-[\d]{{2}}-[\d]{{2}}$", version);
-    let pattern = Regex::new(pattern.as_str())?;
     let input = File::open(&path)?;
     let reader = BufReader::new(input);
     let mut found = false;
     let mut text = String::new();
     for line in reader.lines() {
         let line = line?;
+        let heading = format.version(&line);
         if !found {
-            found = pattern.is_match(&line);
+            found = heading == Some(version);
+        } else if heading.is_some() {
+            break;
+        } else if text.is_empty() && is_heading_underline(&line) {
+            continue;
         } else {
-            if line.starts_with("## ") {
-                break;
-            }
             text.push_str(&line);
             text.push('\n');
         }
@@ -174,7 +302,7 @@ fn get_repo_changelog<P: AsRef<Path>>(
 }
 
 fn get_project_name(url: &str) -> Option<String> {
-    let project = url.split('/').last()?;
+    let project = url.split('/').next_back()?;
     let name = match project.find(".git") {
         Some(pos) => String::from(&project[..pos]),
         None => project.to_string(),
@@ -191,34 +319,84 @@ fn extract_semantic(version: &str) -> Result<&str> {
             3 => caps.get(2).map(|m| m.as_str()),
             _ => None,
         })
-        .ok_or(anyhow!("Invalid version"))
+        .ok_or_else(|| ErrorKind::Validation.error("Invalid version"))
 }
 
 /// Represent a project
 #[derive(Debug)]
 struct Project {
     path: PathBuf,
+    git: git::Options,
     changelog: PathBuf,
+    changelog_format: String,
+    remote: String,
     loose: bool,
 }
 
+/// Name of the file listing emails never to add as contributors
+const OPTOUT_FILENAME: &str = ".kemenn-optout";
+
 impl Project {
     /// Create a `Project`
     fn new<P: AsRef<Path>>(path: P) -> Self {
         Project {
             path: PathBuf::from(path.as_ref()),
+            git: git::Options::default(),
             changelog: PathBuf::from("NEWS.md"),
+            changelog_format: "keepachangelog".to_string(),
+            remote: "origin".to_string(),
             loose: false,
         }
     }
 
+    /// Return the authors of the commits of release `version`
+    ///
+    /// Authors listed in the opt-out file of the repository are left out.
+    /// Return the authors and the number of those left out.
+    fn contributors(&self, version: &str) -> Result<(Vec<String>, usize)> {
+        let range = match git::previous_tag(
+            &self.path, &self.git, version, true, None,
+        )? {
+            Some(previous) => format!("{}..{}", previous, version),
+            None => version.to_string(),
+        };
+        let options = git::LogOptions {
+            format: Some("%aN <%aE>".to_string()),
+            ..Default::default()
+        };
+        let text = git::log(&self.path, &self.git, &range, &options)?;
+        let optout: HashSet<String> =
+            match fs::read_to_string(self.path.join(OPTOUT_FILENAME)) {
+                Ok(text) => text
+                    .lines()
+                    .map(str::trim)
+                    .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                    .map(|l| split_address(l).1.to_lowercase())
+                    .collect(),
+                Err(_) => HashSet::new(),
+            };
+        let mut seen = HashSet::new();
+        let mut filtered = HashSet::new();
+        let mut contributors = Vec::new();
+        for author in text.lines() {
+            let email = split_address(author).1.to_lowercase();
+            if optout.contains(&email) {
+                filtered.insert(email);
+            } else if seen.insert(email) {
+                contributors.push(author.to_string());
+            }
+        }
+        Ok((contributors, filtered.len()))
+    }
+
     /// Explore to get latest release information
     fn release_info(&self, version: &Option<String>) -> Result<ReleaseInfo> {
-        let gitdir = self.path.join(".git");
-        let url = get_repo_url(&gitdir)?;
+        let url = git::remote_url(&self.path, &self.git, &self.remote)
+            .context("Failed to get repository URL")?;
         let version = match version.as_ref() {
             Some(version) => version.clone(),
-            None => get_repo_latest_version(&gitdir)?,
+            None => git::latest_tag(&self.path, &self.git, true, None)
+                .context("Failed to get latest version")?,
         };
         let sem_version = if !self.loose {
             extract_semantic(&version)?
@@ -229,29 +407,87 @@ impl Project {
             .ok_or(anyhow!("Failed to extract project name from URL"))?;
         let mut path = PathBuf::from(&self.path);
         path.push(&self.changelog);
-        let changelog = get_repo_changelog(&path, sem_version)?;
+        let format = HeadingFormat::new(&self.changelog_format)
+            .context("Invalid changelog format")?;
+        let changelog = get_repo_changelog(&path, sem_version, &format)
+            .context("Failed to read changelog")?;
         let info = ReleaseInfo {
-            project: project,
-            url: url,
-            version: version,
-            changelog: changelog,
+            project,
+            url,
+            version,
+            changelog,
         };
         Ok(info)
     }
 
+    /// Check that release information resolves, printing it
+    fn check(&self, version: &Option<String>) -> Result<()> {
+        let info = self.release_info(version)?;
+        println!("url: {}", info.url);
+        println!("project: {}", info.project);
+        println!("version: {}", info.version);
+        if info.changelog.trim().is_empty() {
+            return Err(ErrorKind::Validation.error(format!(
+                "No changelog section found for version {}",
+                info.version
+            )));
+        }
+        println!("changelog: {} line(s)", info.changelog.lines().count());
+        Ok(())
+    }
+
     fn set_changelog<P: AsRef<Path>>(&mut self, filename: P) {
         self.changelog = PathBuf::from(filename.as_ref());
     }
 
+    fn set_changelog_format(&mut self, format: &str) {
+        self.changelog_format = format.to_string();
+    }
+
+    fn set_remote(&mut self, remote: &str) {
+        self.remote = remote.to_string();
+    }
+
+    fn set_show_commands(&mut self, show: bool) {
+        self.git.show_commands = show;
+    }
+
+    fn set_strict_utf8(&mut self, strict: bool) {
+        self.git.strict_utf8 = strict;
+    }
+
     fn set_loose(&mut self, loose: bool) {
         self.loose = loose;
     }
 }
 
+/// Convert a Markdown changelog to plain text
+fn markdown_to_text(text: &str) -> String {
+    let mut output = String::new();
+    for line in text.lines() {
+        let trimmed = line.trim_start();
+        let indent = line.len() - trimmed.len();
+        if trimmed.starts_with('#') {
+            output.push_str(trimmed.trim_start_matches('#').trim_start());
+        } else if trimmed.starts_with("- ") || trimmed.starts_with("* ") {
+            output.push_str(&" ".repeat(indent + 2));
+            output.push_str("* ");
+            output.push_str(&trimmed[2..]);
+        } else {
+            output.push_str(line);
+        }
+        output.push('\n');
+    }
+    output
+}
+
 /// Collect data to fill mail template
 #[derive(Debug)]
 struct MailDataBuilder {
     data: HashMap<String, String>,
+    config: HashMap<String, String>,
+    extra: HashMap<String, String>,
+    plain: bool,
 }
 
 impl MailDataBuilder {
@@ -259,7 +495,30 @@ impl MailDataBuilder {
     fn new() -> Self {
         let mut data = HashMap::new();
         data.insert("prefix".to_string(), "ANNOUNCE".to_string());
-        MailDataBuilder { data }
+        let keys = [
+            "cc",
+            "highlights",
+            "in_reply_to",
+            "references",
+            "signature",
+            "tarball_size",
+            "tarball_sha256",
+        ];
+        for key in &keys {
+            data.insert(key.to_string(), String::new());
+        }
+        MailDataBuilder {
+            data,
+            config: HashMap::new(),
+            extra: HashMap::new(),
+            plain: false,
+        }
+    }
+
+    /// Insert the changelog as plain text rather than a Markdown block
+    fn plain_changelog(&mut self, plain: bool) -> &mut Self {
+        self.plain = plain;
+        self
     }
 
     fn emitter(&mut self, emitter: &str) -> &mut Self {
@@ -277,29 +536,88 @@ impl MailDataBuilder {
         self
     }
 
+    fn cc<S: AsRef<str>>(&mut self, cc: &[S]) -> &mut Self {
+        let cc = cc
+            .iter()
+            .map(AsRef::as_ref)
+            .collect::<Vec<&str>>()
+            .join(", ");
+        self.data.insert("cc".to_string(), cc);
+        self
+    }
+
     fn info(&mut self, info: &ReleaseInfo) -> &mut Self {
         self.data
             .insert("project".to_string(), info.project.clone());
         self.data.insert("url".to_string(), info.url.clone());
         self.data
             .insert("version".to_string(), info.version.clone());
-        let text = format!("What's new?\n\n```\n{}```", info.changelog);
+        let text = if self.plain {
+            format!("What's new?\n\n{}", markdown_to_text(&info.changelog))
+        } else {
+            format!("What's new?\n\n```\n{}```", info.changelog)
+        };
         self.data.insert("text".to_string(), text);
         self
     }
 
+    /// Set the release highlights, inserted above the changelog
+    fn highlights(&mut self, text: &str) -> &mut Self {
+        self.data
+            .insert("highlights".to_string(), text.trim_end().to_string());
+        self
+    }
+
+    fn message_id(&mut self, message_id: &str) -> &mut Self {
+        self.data
+            .insert("message_id".to_string(), message_id.to_string());
+        self
+    }
+
+    fn in_reply_to(&mut self, message_id: &str) -> &mut Self {
+        let message_id = if message_id.starts_with('<') {
+            message_id.to_string()
+        } else {
+            format!("<{}>", message_id)
+        };
+        self.data
+            .insert("in_reply_to".to_string(), message_id.clone());
+        self.data.insert("references".to_string(), message_id);
+        self
+    }
+
     fn signature(&mut self, text: &str) -> &mut Self {
         self.data.insert("signature".to_string(), text.to_string());
         self
     }
 
+    fn tarball(&mut self, size: u64, sha256: &str) -> &mut Self {
+        self.data
+            .insert("tarball_size".to_string(), format_size(size));
+        self.data
+            .insert("tarball_sha256".to_string(), sha256.to_string());
+        self
+    }
+
+    /// Set parameters read from a file, overriding built-in values
+    fn config(&mut self, data: HashMap<String, String>) -> &mut Self {
+        self.config.extend(data);
+        self
+    }
+
+    /// Set parameters given on the command line, overriding all others
     fn extra(&mut self, data: HashMap<String, String>) -> &mut Self {
-        self.data.extend(data);
+        self.extra.extend(data);
         self
     }
 
     /// Consume a `MailDataBuilder`
-    fn build(self) -> HashMap<String, String> {
+    ///
+    /// Command line parameters take precedence over parameters from a file,
+    /// which take precedence over built-in values.
+    fn build(mut self) -> HashMap<String, String> {
+        self.data.extend(self.config);
+        self.data.extend(self.extra);
         self.data
     }
 }
@@ -307,11 +625,17 @@ impl MailDataBuilder {
 /// Build a mail
 struct MailBuilder {
     template: Option<String>,
+    partials: HashMap<String, String>,
+    strict: bool,
 }
 
 impl MailBuilder {
     fn new() -> Self {
-        MailBuilder { template: None }
+        MailBuilder {
+            template: None,
+            partials: HashMap::new(),
+            strict: false,
+        }
     }
 
     fn template(&mut self, template: &str) -> &mut Self {
@@ -319,60 +643,318 @@ impl MailBuilder {
         self
     }
 
+    /// Register a partial, to be included as `{{> name}}`
+    fn partial(&mut self, name: &str, template: &str) -> &mut Self {
+        self.partials.insert(name.to_string(), template.to_string());
+        self
+    }
+
+    /// Make rendering fail when the template references an unknown variable
+    fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
     fn build(self, data: &HashMap<String, String>) -> Result<String> {
-        let template = self
-            .template
-            .as_ref()
-            .map(String::as_str)
-            .unwrap_or(DEFAULT_TEMPLATE);
+        let template = self.template.as_deref().unwrap_or(DEFAULT_TEMPLATE);
         let mut handlebars = Handlebars::new();
         handlebars.register_escape_fn(no_escape);
-        handlebars.register_template_string("t", template)?;
-        let text = handlebars.render("t", data)?;
+        handlebars.set_strict_mode(self.strict);
+        let invalid = |e: handlebars::TemplateError| {
+            ErrorKind::Input.error(format!("Invalid template ({})", e))
+        };
+        for (name, partial) in &self.partials {
+            handlebars
+                .register_partial(name, partial)
+                .map_err(invalid)?;
+        }
+        handlebars
+            .register_template_string("t", template)
+            .map_err(invalid)?;
+        let text = handlebars.render("t", data).map_err(|e| {
+            ErrorKind::Input.error(format!("Failed to render template ({})", e))
+        })?;
         Ok(text)
     }
 }
 
+/// Read the partials of a directory, named after their file stem
+fn read_partials<P: AsRef<Path>>(dir: P) -> Result<HashMap<String, String>> {
+    let mut partials = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+            partials.insert(name.to_string(), fs::read_to_string(&path)?);
+        }
+    }
+    Ok(partials)
+}
+
+fn get_hostname() -> Option<String> {
+    env::var("HOSTNAME").ok()
+}
+
 fn get_logged_user_email() -> Option<String> {
     let username = env::var("USER").or(env::var("USERNAME")).ok()?;
-    env::var("HOSTNAME")
-        .map(|h| format!("{}@{}", username, h))
-        .ok()
+    get_hostname().map(|h| format!("{}@{}", username, h))
+}
+
+fn generate_message_id() -> String {
+    let timestamp = Utc::now().format("%Y%m%d%H%M%S");
+    let random = RandomState::new().build_hasher().finish();
+    let hostname = get_hostname().unwrap_or_else(|| "localhost".to_string());
+    format!("<{}.{:016x}@{}>", timestamp, random, hostname)
 }
 
 fn get_user_email() -> Option<String> {
-    if let Ok(email) = env::var("DEBEMAIL") {
-        let emitter = env::var("DEBFULLNAME")
-            .map(|f| format!("{} <{}>", f, email))
-            .unwrap_or(email);
-        return Some(emitter);
+    env::var("DEBEMAIL")
+        .or_else(|_| env::var("EMAIL"))
+        .ok()
+        .or_else(get_logged_user_email)
+}
+
+fn get_user_name() -> Option<String> {
+    env::var("DEBFULLNAME").or_else(|_| env::var("NAME")).ok()
+}
+
+/// Split an address like `Full Name <email>` into its name and email
+fn split_address(address: &str) -> (Option<&str>, &str) {
+    let address = address.trim();
+    match (address.find('<'), address.strip_suffix('>')) {
+        (Some(pos), Some(rest)) => {
+            let name = address[..pos].trim().trim_matches('"');
+            let name = if name.is_empty() { None } else { Some(name) };
+            (name, rest[pos + 1..].trim())
+        }
+        _ => (None, address),
     }
-    env::var("EMAIL").ok().or(get_logged_user_email())
 }
 
+/// Check that an email address looks valid
+fn is_valid_email(email: &str) -> bool {
+    let mut parts = email.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(local), Some(domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !email.contains(|c: char| c.is_whitespace() || c == '<')
+        }
+        _ => false,
+    }
+}
+
+/// Format an address as `Full Name <email>`, or a bare email without name
+fn format_address(name: Option<&str>, email: &str) -> Result<String> {
+    if !is_valid_email(email) {
+        return Err(
+            ErrorKind::Input.error(format!("Invalid email address: {}", email))
+        );
+    }
+    match name {
+        Some(name) => Ok(format!("{} <{}>", name, email)),
+        None => Ok(email.to_string()),
+    }
+}
+
+/// Parse a parameter given as `K:V` or `K=V`
+///
+/// The key ends at the first separator, so the value may hold colons, like
+/// in an URL. Leading whitespace of the value is dropped.
 fn parse_parameter(s: &str) -> Option<(String, String)> {
-    let mut split = s.splitn(2, ':');
-    if let Some(key) = split.next() {
-        if let Some(value) = split.next() {
-            return Some((key.to_string(), value.to_string()));
+    let pos = s.find([':', '='])?;
+    let key = s[..pos].trim();
+    if key.is_empty() {
+        return None;
+    }
+    let value = s[pos + 1..].trim_start();
+    Some((key.to_string(), value.to_string()))
+}
+
+/// Read extra parameters from a TOML file, or a file of K:V lines
+fn read_parameters_file<P: AsRef<Path>>(
+    path: P,
+) -> Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)?;
+    if let Ok(toml::Value::Table(table)) = text.parse::<toml::Value>() {
+        let parameters = table
+            .into_iter()
+            .map(|(key, value)| match value {
+                toml::Value::String(value) => (key, value),
+                value => (key, value.to_string()),
+            })
+            .collect();
+        return Ok(parameters);
+    }
+    let parameters = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(parse_parameter)
+        .collect();
+    Ok(parameters)
+}
+
+fn add_recipients_from_groups(
+    recipients: &mut Vec<String>,
+    table: &toml::value::Table,
+    groups: &[String],
+) -> Result<()> {
+    let names: Vec<&String> = if groups.is_empty() {
+        table.keys().collect()
+    } else {
+        groups.iter().collect()
+    };
+    for name in names {
+        let members = table
+            .get(name)
+            .ok_or_else(|| {
+                ErrorKind::Input
+                    .error(format!("Unknown recipient group: {}", name))
+            })?
+            .as_array()
+            .ok_or_else(|| {
+                ErrorKind::Input
+                    .error(format!("Invalid recipient group: {}", name))
+            })?;
+        for member in members {
+            let member = member.as_str().ok_or_else(|| {
+                ErrorKind::Input
+                    .error(format!("Invalid recipient in group {}", name))
+            })?;
+            recipients.push(member.to_string());
         }
     }
-    None
+    Ok(())
 }
 
 fn add_recipients_from_path<P: AsRef<Path>>(
     recipients: &mut Vec<String>,
     path: P,
+    groups: &[String],
 ) -> Result<()> {
-    let input = File::open(path)?;
-    let reader = BufReader::new(input);
-    for line in reader.lines() {
-        let line = line?;
-        recipients.push(line);
+    let text = fs::read_to_string(path)?;
+    if let Ok(toml::Value::Table(table)) = text.parse::<toml::Value>() {
+        return add_recipients_from_groups(recipients, &table, groups);
+    }
+    if !groups.is_empty() {
+        return Err(ErrorKind::Input
+            .error("Recipient groups require a TOML input file"));
+    }
+    let lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'));
+    recipients.extend(lines.map(String::from));
+    Ok(())
+}
+
+/// Remove blank and duplicate recipients, keeping the first seen
+///
+/// Recipients are compared by their email, ignoring case.
+fn dedup_recipients(recipients: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    recipients.retain(|r| {
+        let email = split_address(r).1;
+        !email.is_empty() && seen.insert(email.to_lowercase())
+    });
+}
+
+/// Collect recipients from the command line and the input file
+fn collect_recipients(
+    recipients: &mut Vec<String>,
+    input: Option<&Path>,
+    groups: &[String],
+) -> Result<()> {
+    if let Some(input) = input {
+        add_recipients_from_path(recipients, input, groups)
+            .context("Failed to add recipients from input")?;
+    } else if !groups.is_empty() {
+        return Err(
+            ErrorKind::Input.error("Recipient groups require an input file")
+        );
     }
+    dedup_recipients(recipients);
     Ok(())
 }
 
+fn truncate_value(value: &str, max_len: usize) -> String {
+    let mut lines = value.lines();
+    let line = lines.next().unwrap_or("");
+    if line.chars().count() > max_len || lines.next().is_some() {
+        let text: String = line.chars().take(max_len).collect();
+        return format!("{}...", text);
+    }
+    line.to_string()
+}
+
+fn list_vars(data: &HashMap<String, String>) -> Result<()> {
+    let mut keys: Vec<&String> = data.keys().collect();
+    keys.sort();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for key in keys {
+        writeln!(stdout, "{}: {}", key, truncate_value(&data[key], 40))?;
+    }
+    Ok(())
+}
+
+/// Format a size in bytes with a decimal unit, e.g. "12.3 MB"
+fn format_size(size: u64) -> String {
+    const UNITS: &[&str] = &["kB", "MB", "GB", "TB"];
+    if size < 1000 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1000.0;
+    let mut unit = UNITS[0];
+    for next in &UNITS[1..] {
+        if value < 1000.0 {
+            break;
+        }
+        value /= 1000.0;
+        unit = *next;
+    }
+    format!("{:.1} {}", value, unit)
+}
+
+/// Return the size and the SHA256 digest of a file
+fn get_file_digest<P: AsRef<Path>>(path: P) -> Result<(u64, String)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    let mut size = 0;
+    loop {
+        let count = file.read(&mut buffer)?;
+        if count == 0 {
+            break;
+        }
+        hasher.update(&buffer[..count]);
+        size += count as u64;
+    }
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
+
+/// Wrap the body of a mail to `width` columns, leaving the headers and the
+/// signature intact
+fn wrap_mail_body(mail: &str, width: usize) -> String {
+    match mail.find("\n\n") {
+        Some(pos) => {
+            let (headers, body) = mail.split_at(pos + 2);
+            let end = if body.starts_with("-- \n") {
+                0
+            } else {
+                body.find("\n-- \n").map_or(body.len(), |pos| pos + 1)
+            };
+            let (text, signature) = body.split_at(end);
+            format!("{}{}{}", headers, wrap_text(text, width), signature)
+        }
+        None => mail.to_string(),
+    }
+}
+
 fn get_signature() -> Option<String> {
     if let Some(mut path) = dirs::home_dir() {
         path.push(".signature");
@@ -382,22 +964,37 @@ fn get_signature() -> Option<String> {
     None
 }
 
-fn main() -> Result<()> {
-    let mut opts = KemennOpts::from_args();
-    let emitter = opts
-        .emitter
-        .or_else(get_user_email)
-        .ok_or(anyhow!("Missing emitter email"))?;
-    if let Some(input) = opts.input {
-        add_recipients_from_path(&mut opts.recipients, input)
-            .context("Failed to add recipients from input")?;
-    }
+/// Run kemenn with options `opts`
+pub fn run(mut opts: KemennOpts) -> Result<()> {
     let mut project = Project::new(&opts.repository);
+    project.set_show_commands(opts.show_commands);
+    project.set_strict_utf8(opts.strict_utf8);
     if let Some(changelog) = opts.changelog {
         project.set_changelog(&changelog);
     }
+    project.set_changelog_format(&opts.changelog_format);
+    project.set_remote(&opts.remote);
     project.set_loose(opts.loose);
+    if opts.check {
+        return project.check(&opts.release);
+    }
 
+    let emitter = opts
+        .emitter
+        .or_else(get_user_email)
+        .ok_or_else(|| ErrorKind::Input.error("Missing emitter email"))?;
+    let (name, email) = split_address(&emitter);
+    let name = opts
+        .name
+        .clone()
+        .or_else(|| name.map(String::from))
+        .or_else(get_user_name);
+    let emitter = format_address(name.as_deref(), email)?;
+    collect_recipients(
+        &mut opts.recipients,
+        opts.input.as_deref(),
+        &opts.groups,
+    )?;
     let info = project
         .release_info(&opts.release)
         .context("Failed to get release info")?;
@@ -405,9 +1002,43 @@ fn main() -> Result<()> {
     builder
         .emitter(&emitter)
         .recipients(&opts.recipients)
+        .plain_changelog(opts.plain_changelog)
         .info(&info);
-    if let Some(signature) = get_signature() {
-        builder.signature(&signature);
+    builder.message_id(&generate_message_id());
+    if opts.cc_contributors {
+        let (contributors, filtered) = project
+            .contributors(&info.version)
+            .context("Failed to get contributors")?;
+        if filtered > 0 {
+            eprintln!(
+                "{} contributor(s) left out by {}",
+                filtered, OPTOUT_FILENAME
+            );
+        }
+        builder.cc(&contributors);
+    }
+    if let Some(path) = opts.highlights {
+        let text =
+            fs::read_to_string(path).context("Failed to read highlights")?;
+        builder.highlights(&text);
+    }
+    if let Some(message_id) = opts.in_reply_to {
+        builder.in_reply_to(&message_id);
+    }
+    if !opts.no_signature {
+        if let Some(signature) = get_signature() {
+            builder.signature(&signature);
+        }
+    }
+    if let Some(tarball) = opts.tarball {
+        let (size, sha256) =
+            get_file_digest(tarball).context("Failed to read tarball")?;
+        builder.tarball(size, &sha256);
+    }
+    if let Some(path) = opts.parameters_file {
+        let parameters = read_parameters_file(path)
+            .context("Failed to read parameters file")?;
+        builder.config(parameters);
     }
     if let Some(parameters) = opts.parameters {
         let parameters: HashMap<String, String> = parameters
@@ -417,19 +1048,252 @@ fn main() -> Result<()> {
         builder.extra(parameters);
     }
     let data = builder.build();
+    if opts.list_vars {
+        return list_vars(&data);
+    }
     let mut builder = MailBuilder::new();
     if let Some(template) = opts.template {
-        let text =
-            fs::read_to_string(template).context("Failed to read template")?;
+        let text = fs::read_to_string(template).map_err(|e| {
+            ErrorKind::Input.error(format!("Failed to read template ({})", e))
+        })?;
         builder.template(&text);
     }
-    let text = builder.build(&data)?;
-    if let Some(output) = opts.output {
-        fs::write(output, text).context("Failed to write output")?;
-    } else {
-        let stdout = io::stdout();
-        let mut stdout = stdout.lock();
-        stdout.write_all(text.as_bytes())?;
+    if let Some(dir) = opts.partials_dir {
+        let partials = read_partials(dir).map_err(|e| {
+            ErrorKind::Input
+                .error(format!("Failed to read template partials ({})", e))
+        })?;
+        for (name, partial) in &partials {
+            builder.partial(name, partial);
+        }
     }
+    builder.strict(opts.strict_template);
+    let text = builder.build(&data).context("Failed to render template")?;
+    let text = match opts.wrap {
+        Some(width) => wrap_mail_body(&text, width),
+        None => text,
+    };
+    let text = if opts.crlf {
+        text.replace("\r\n", "\n").replace('\n', "\r\n")
+    } else {
+        text
+    };
+    let mut output =
+        open_output(opts.output.as_deref()).context("Failed to open output")?;
+    output
+        .write_all(text.as_bytes())
+        .and_then(|_| output.flush())
+        .context("Failed to write output")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exit_code;
+    use crate::scratch::{commit_as, create_repo, git};
+    use tempfile::TempDir;
+
+    fn param(key: &str, value: &str) -> Option<(String, String)> {
+        Some((key.to_string(), value.to_string()))
+    }
+
+    fn release(authors: &[&str]) -> TempDir {
+        let repo = create_repo(&[]);
+        for author in authors {
+            commit_as(repo.path(), author, "Update docs");
+        }
+        git(
+            repo.path(),
+            &["tag", "-a", "-m", "Release v0.2.0", "v0.2.0"],
+        );
+        repo
+    }
+
+    #[test]
+    fn contributors_are_unique_per_email() {
+        let repo = release(&[
+            "J. Doe <JANE@example.com>",
+            "John Roe <john@example.com>",
+            "Jane Doe <jane@example.com>",
+        ]);
+        let project = Project::new(repo.path());
+        let (contributors, filtered) = project.contributors("v0.2.0").unwrap();
+        assert_eq!(
+            contributors,
+            vec!["Jane Doe <jane@example.com>", "John Roe <john@example.com>"]
+        );
+        assert_eq!(filtered, 0);
+    }
+
+    #[test]
+    fn contributors_start_at_previous_tag() {
+        let repo = release(&["John Roe <john@example.com>"]);
+        let project = Project::new(repo.path());
+        let (contributors, _) = project.contributors("v0.2.0").unwrap();
+        assert_eq!(contributors, vec!["John Roe <john@example.com>"]);
+        let (contributors, _) = project.contributors("v0.1.0").unwrap();
+        assert_eq!(contributors, vec!["Jane Doe <jane@example.com>"]);
+    }
+
+    #[test]
+    fn contributors_skip_opt_out() {
+        let repo = release(&[
+            "John Roe <john@example.com>",
+            "Jane Doe <jane@example.com>",
+            "John Roe <John@example.com>",
+        ]);
+        fs::write(
+            repo.path().join(OPTOUT_FILENAME),
+            "# Authors asking not to be listed\nJohn Roe <john@example.com>\n",
+        )
+        .unwrap();
+        let project = Project::new(repo.path());
+        let (contributors, filtered) = project.contributors("v0.2.0").unwrap();
+        assert_eq!(contributors, vec!["Jane Doe <jane@example.com>"]);
+        assert_eq!(filtered, 1);
+    }
+
+    #[test]
+    fn wrap_mail_body_keeps_headers() {
+        let mail = "Subject: [ANNOUNCE] foo 1.0 is available\n\n\
+                    Version 1.0 of foo is available.\n\n\
+                    ```\n- Add a very long line to the changelog\n```\n";
+        assert_eq!(
+            wrap_mail_body(mail, 20),
+            "Subject: [ANNOUNCE] foo 1.0 is available\n\n\
+             Version 1.0 of foo\nis available.\n\n\
+             ```\n- Add a very long line to the changelog\n```\n"
+        );
+    }
+
+    #[test]
+    fn wrap_mail_body_keeps_signature() {
+        let mail = "Subject: [ANNOUNCE] foo 1.0 is available\n\n\
+                    Version 1.0 of foo is available.\n\n\
+                    -- \nJane Doe, maintainer of foo and bar\n";
+        assert_eq!(
+            wrap_mail_body(mail, 20),
+            "Subject: [ANNOUNCE] foo 1.0 is available\n\n\
+             Version 1.0 of foo\nis available.\n\n\
+             -- \nJane Doe, maintainer of foo and bar\n"
+        );
+    }
+
+    #[test]
+    fn dedup_recipients_ignores_case() {
+        let mut recipients = vec![
+            "jane@example.com".to_string(),
+            "".to_string(),
+            "John <john@example.com>".to_string(),
+            "Jane <JANE@example.com>".to_string(),
+            "john@EXAMPLE.com".to_string(),
+        ];
+        dedup_recipients(&mut recipients);
+        assert_eq!(
+            recipients,
+            vec!["jane@example.com", "John <john@example.com>"]
+        );
+    }
+
+    #[test]
+    fn mail_builder_includes_partials() {
+        let mut data = HashMap::new();
+        data.insert("project".to_string(), "foo".to_string());
+        let mut builder = MailBuilder::new();
+        builder
+            .template("{{> header}}Body\n")
+            .partial("header", "Project: {{project}}\n");
+        assert_eq!(builder.build(&data).unwrap(), "Project: foo\nBody\n");
+    }
+
+    #[test]
+    fn template_errors_are_input_errors() {
+        let data = HashMap::new();
+        let mut builder = MailBuilder::new();
+        builder.template("{{#if}}");
+        assert_eq!(exit_code(&builder.build(&data).unwrap_err()), 2);
+        let mut builder = MailBuilder::new();
+        builder.template("{{project}}").strict(true);
+        assert_eq!(exit_code(&builder.build(&data).unwrap_err()), 2);
+    }
+
+    #[test]
+    fn recipient_group_errors_are_input_errors() {
+        let table = "team = [\"jane@example.com\"]\nbad = 1\nodd = [1]\n"
+            .parse::<toml::Value>()
+            .unwrap();
+        let table = table.as_table().unwrap();
+        let mut recipients = Vec::new();
+        for group in &["unknown", "bad", "odd"] {
+            let groups = vec![group.to_string()];
+            let e = add_recipients_from_groups(&mut recipients, table, &groups)
+                .unwrap_err();
+            assert_eq!(exit_code(&e), 2);
+        }
+    }
+
+    #[test]
+    fn default_template_inserts_highlights() {
+        let mut builder = MailDataBuilder::new();
+        builder
+            .emitter("jane@example.com")
+            .recipients(&["a@example.com"]);
+        let data = builder.build();
+        let text = MailBuilder::new().build(&data).unwrap();
+        assert!(text.contains("[1] \n\n\n\nRegards"));
+
+        let mut builder = MailDataBuilder::new();
+        builder
+            .emitter("jane@example.com")
+            .recipients(&["a@example.com"])
+            .highlights("- Faster parser\n\n");
+        let data = builder.build();
+        let text = MailBuilder::new().build(&data).unwrap();
+        assert!(text.contains("[1] \n\n- Faster parser\n\n\n\nRegards"));
+    }
+
+    #[test]
+    fn mail_data_parameters_precedence() {
+        let params = |pairs: &[(&str, &str)]| -> HashMap<String, String> {
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let mut builder = MailDataBuilder::new();
+        builder
+            .extra(params(&[("prefix", "SECURITY")]))
+            .config(params(&[("prefix", "RELEASE"), ("team", "core")]));
+        let data = builder.build();
+        assert_eq!(data["prefix"], "SECURITY");
+        assert_eq!(data["team"], "core");
+
+        let mut builder = MailDataBuilder::new();
+        builder.config(params(&[("prefix", "RELEASE")]));
+        assert_eq!(builder.build()["prefix"], "RELEASE");
+        assert_eq!(MailDataBuilder::new().build()["prefix"], "ANNOUNCE");
+    }
+
+    #[test]
+    fn parse_parameter_keeps_colons_in_value() {
+        assert_eq!(
+            parse_parameter("key:https://example.com"),
+            param("key", "https://example.com")
+        );
+        assert_eq!(
+            parse_parameter("key=https://example.com"),
+            param("key", "https://example.com")
+        );
+    }
+
+    #[test]
+    fn parse_parameter_trims_value() {
+        assert_eq!(
+            parse_parameter("key: spaced value"),
+            param("key", "spaced value")
+        );
+        assert_eq!(parse_parameter("key"), None);
+        assert_eq!(parse_parameter(":value"), None);
+    }
+}
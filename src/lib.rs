@@ -6,17 +6,523 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 //
 
-use anyhow::{anyhow, Result};
-use std::process::Command;
-use std::str;
+use anyhow::Result;
+use std::env;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{self, Command};
+use std::thread;
+use std::time::Duration;
+use structopt::clap::{self, Shell};
+use structopt::StructOpt;
 
-pub fn run_command_or(command: &mut Command, error: &str) -> Result<String> {
-    let output = command.output()?;
+pub mod changelog;
+pub mod git;
+pub mod kemenn;
+pub mod nevez;
+pub mod zrdm;
+
+#[cfg(test)]
+mod scratch;
+
+/// Write shell completions to stdout if asked with `--generate-completions`
+///
+/// This is checked before parsing the command line, so that the required
+/// arguments of the tool do not have to be given. Return `true` if the
+/// completions were written.
+pub fn generate_completions<S: StructOpt>(name: &str) -> Result<bool> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let pos = match args.iter().position(|a| a == "--generate-completions") {
+        Some(pos) => pos,
+        None => return Ok(false),
+    };
+    let shell = args
+        .get(pos + 1)
+        .ok_or_else(|| ErrorKind::Input.error("Missing shell for completions"))?
+        .parse::<Shell>()
+        .map_err(|e| ErrorKind::Input.error(e))?;
+    S::clap().gen_completions_to(name, shell, &mut io::stdout());
+    Ok(true)
+}
+
+/// Escape a line of text for roff
+fn roff_escape(line: &str) -> String {
+    let line = line.replace('\\', "\\e");
+    if line.starts_with('.') || line.starts_with('\'') {
+        return format!("\\&{}", line);
+    }
+    line
+}
+
+/// Format the help text of a tool as a man page
+fn format_man_page(name: &str, help: &str) -> String {
+    let mut lines = help.lines();
+    lines.next();
+    let about: Vec<&str> =
+        lines.by_ref().take_while(|l| !l.is_empty()).collect();
+    let mut page = format!(
+        ".TH {} 1\n.SH NAME\n{} \\- {}\n",
+        name.to_uppercase(),
+        name,
+        roff_escape(&about.join(" "))
+    );
+    let mut in_section = false;
+    for line in lines {
+        if !line.starts_with(' ') && line.ends_with(':') {
+            if in_section {
+                page.push_str(".fi\n");
+            }
+            page.push_str(".SH ");
+            page.push_str(line.trim_end_matches(':'));
+            page.push_str("\n.nf\n");
+            in_section = true;
+        } else if in_section {
+            page.push_str(&roff_escape(line));
+            page.push('\n');
+        }
+    }
+    if in_section {
+        page.push_str(".fi\n");
+    }
+    page
+}
+
+/// Write a man page to stdout if asked with `--generate-man`
+///
+/// Like `generate_completions`, this is checked before parsing the command
+/// line. Return `true` if the man page was written.
+pub fn generate_man_page<S: StructOpt>(name: &str) -> Result<bool> {
+    if !env::args().skip(1).any(|a| a == "--generate-man") {
+        return Ok(false);
+    }
+    let mut help = Vec::new();
+    S::clap().write_long_help(&mut help)?;
+    let help = String::from_utf8(help)?;
+    let page = format_man_page(name, &help);
+    io::stdout().write_all(page.as_bytes())?;
+    Ok(true)
+}
+
+/// Parse the command line of a tool
+///
+/// Usage errors are reported as `ErrorKind::Input` failures, while help and
+/// version requests are printed before exiting successfully.
+pub fn parse_args<S: StructOpt>() -> Result<S> {
+    parse_args_from(env::args_os())
+}
+
+/// Parse `args` as the command line of a tool, like `parse_args`
+fn parse_args_from<S, I>(args: I) -> Result<S>
+where
+    S: StructOpt,
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    S::from_iter_safe(args).map_err(|e| match e.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => {
+            e.exit()
+        }
+        _ => {
+            let message = e.message.trim_start_matches("error: ");
+            ErrorKind::Input.error(message)
+        }
+    })
+}
+
+/// Kind of failure, telling the exit code of the tools
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// Invalid input, like a malformed option value or file
+    Input,
+    /// Input is well-formed but does not pass checks
+    Validation,
+    /// An external command failed
+    Command,
+}
+
+impl ErrorKind {
+    /// Return the exit code matching this kind of failure
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Input => 2,
+            ErrorKind::Validation => 3,
+            ErrorKind::Command => 4,
+        }
+    }
+
+    /// Create an error of this kind
+    pub fn error<S: Into<String>>(self, message: S) -> anyhow::Error {
+        anyhow::Error::new(Failure {
+            kind: self,
+            message: message.into(),
+        })
+    }
+}
+
+/// Error tagged with its kind
+#[derive(Debug)]
+struct Failure {
+    kind: ErrorKind,
+    message: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for Failure {}
+
+/// Return the exit code matching `error`, 1 if its kind is unknown
+pub fn exit_code(error: &anyhow::Error) -> i32 {
+    error
+        .chain()
+        .find_map(|e| e.downcast_ref::<Failure>())
+        .map_or(1, |f| f.kind.exit_code())
+}
+
+/// Report the error of `result` if any, and exit with the matching code
+pub fn exit_on_error(result: Result<()>) {
+    if let Err(error) = result {
+        eprintln!("Error: {:?}", error);
+        process::exit(exit_code(&error));
+    }
+}
+
+/// Environment making the output of git reproducible and locale-independent
+pub const GIT_ENV: &[(&str, &str)] =
+    &[("LC_ALL", "C"), ("GIT_CONFIG_NOSYSTEM", "1")];
+
+/// Create a `git` command, with `GIT_ENV` set
+pub fn git_command() -> Command {
+    let mut command = Command::new("git");
+    command.envs(GIT_ENV.iter().cloned());
+    command
+}
+
+/// Decode the output of a command as UTF-8
+///
+/// Invalid sequences are replaced, unless `strict` is set.
+pub fn decode_output(bytes: Vec<u8>, strict: bool) -> Result<String> {
+    if strict {
+        return Ok(String::from_utf8(bytes)?);
+    }
+    match String::from_utf8(bytes) {
+        Ok(text) => Ok(text),
+        Err(e) => Ok(String::from_utf8_lossy(e.as_bytes()).into_owned()),
+    }
+}
+
+/// Run `command`, returning its output if it succeeds
+///
+/// Decoding fails on invalid UTF-8 if `strict` is set.
+fn try_command(
+    command: &mut Command,
+    error: &str,
+    strict: bool,
+) -> Result<Option<String>> {
+    let output = command
+        .output()
+        .map_err(|e| ErrorKind::Command.error(format!("{} ({})", error, e)))?;
 
     if !output.status.success() {
-        return Err(anyhow!(error.to_string()));
+        return Ok(None);
+    }
+
+    let text = decode_output(output.stdout, strict)?.trim_end().to_string();
+    Ok(Some(text))
+}
+
+pub fn run_command_or(command: &mut Command, error: &str) -> Result<String> {
+    try_command(command, error, false)?
+        .ok_or_else(|| ErrorKind::Command.error(error))
+}
+
+/// Run `command` like `run_command_or`, retrying it when it fails
+///
+/// The command is run at most `attempts` times, and at least once, waiting
+/// `backoff` before the first retry and twice as long before each following
+/// one. It is not retried if it can not be started.
+pub fn run_command_with_retry(
+    command: &mut Command,
+    error: &str,
+    attempts: u32,
+    backoff: Duration,
+) -> Result<String> {
+    let mut delay = backoff;
+    for _ in 1..attempts {
+        if let Some(text) = try_command(command, error, false)? {
+            return Ok(text);
+        }
+        thread::sleep(delay);
+        delay *= 2;
+    }
+    run_command_or(command, error)
+}
+
+/// Run `command` from directory `dir`, like `run_command_or`
+///
+/// Decoding fails on invalid UTF-8 if `strict` is set.
+pub fn run_command_in<P: AsRef<Path>>(
+    command: &mut Command,
+    dir: P,
+    error: &str,
+    strict: bool,
+) -> Result<String> {
+    command.current_dir(dir.as_ref());
+    try_command(command, error, strict)?
+        .ok_or_else(|| ErrorKind::Command.error(error))
+}
+
+/// Open the output of a tool: the file at `path`, or stdout if it is `-`
+/// or not given
+pub fn open_output(path: Option<&Path>) -> io::Result<Box<dyn Write>> {
+    match path {
+        Some(path) if path != Path::new("-") => {
+            Ok(Box::new(io::BufWriter::new(File::create(path)?)))
+        }
+        _ => Ok(Box::new(io::stdout())),
+    }
+}
+
+/// Quote `arg` for a POSIX shell, if needed
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe =
+        |c: char| c.is_ascii_alphanumeric() || "-_./=:,@%+^".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Check if a line of text must be output without reflowing it
+fn is_preformatted(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty()
+        || line.starts_with(char::is_whitespace)
+        || trimmed.starts_with('#')
+        || trimmed.starts_with('|')
+        || trimmed.starts_with("+-")
+        || trimmed.contains(" | ")
+        || trimmed.chars().all(|c| c == '=' || c == '-')
+}
+
+/// Return the length of the marker of a list item, if any
+fn list_marker_len(line: &str) -> Option<usize> {
+    if line.starts_with("- ") || line.starts_with("* ") {
+        return Some(2);
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0 && line[digits..].starts_with(". ") {
+        return Some(digits + 2);
+    }
+    None
+}
+
+/// Fill a paragraph made of `words` to `width` columns
+fn fill_paragraph(
+    output: &mut String,
+    words: &[&str],
+    indent: usize,
+    width: usize,
+) {
+    let mut column = 0;
+    for word in words {
+        if column > indent && column + 1 + word.len() > width {
+            output.push('\n');
+            output.push_str(&" ".repeat(indent));
+            column = indent;
+        } else if column > 0 {
+            output.push(' ');
+            column += 1;
+        }
+        output.push_str(word);
+        column += word.len();
+    }
+    if column > 0 {
+        output.push('\n');
+    }
+}
+
+/// Reflow the paragraphs of a plain text to `width` columns
+///
+/// Blank lines, indented lines, fenced blocks, headings and lines looking
+/// like tables are left untouched.
+pub fn wrap_text(text: &str, width: usize) -> String {
+    let mut output = String::new();
+    let mut words: Vec<&str> = Vec::new();
+    let mut indent = 0;
+    let mut fenced = false;
+    for line in text.lines() {
+        let fence = line.trim_start().starts_with("```");
+        if fenced || fence || is_preformatted(line) {
+            fill_paragraph(&mut output, &words, indent, width);
+            words.clear();
+            output.push_str(line);
+            output.push('\n');
+            if fence {
+                fenced = !fenced;
+            }
+            continue;
+        }
+        if let Some(len) = list_marker_len(line) {
+            fill_paragraph(&mut output, &words, indent, width);
+            words.clear();
+            indent = len;
+        } else if words.is_empty() {
+            indent = 0;
+        }
+        words.extend(line.split_whitespace());
+    }
+    fill_paragraph(&mut output, &words, indent, width);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+    use std::time::Instant;
+    use tempfile::tempdir;
+
+    #[derive(Debug, StructOpt)]
+    struct Opts {
+        #[structopt(long = "count")]
+        count: usize,
+    }
+
+    #[test]
+    fn man_page_from_help() {
+        let help = "tool 1.0\n\
+                    Do things\\with tools\n\
+                    \n\
+                    USAGE:\n    tool [FLAGS]\n\
+                    \n\
+                    NOTES:\n\
+                    .starts with a dot\n\
+                    'starts with a quote\n    C:\\path\n";
+        assert_eq!(
+            format_man_page("tool", help),
+            ".TH TOOL 1\n\
+             .SH NAME\n\
+             tool \\- Do things\\ewith tools\n\
+             .SH USAGE\n.nf\n    tool [FLAGS]\n\n.fi\n\
+             .SH NOTES\n.nf\n\
+             \\&.starts with a dot\n\
+             \\&'starts with a quote\n    C:\\epath\n\
+             .fi\n"
+        );
+    }
+
+    #[test]
+    fn exit_code_follows_error_kind() {
+        assert_eq!(exit_code(&ErrorKind::Input.error("bad")), 2);
+        assert_eq!(exit_code(&ErrorKind::Validation.error("bad")), 3);
+        assert_eq!(exit_code(&ErrorKind::Command.error("bad")), 4);
+        assert_eq!(exit_code(&anyhow!("bad")), 1);
+    }
+
+    #[test]
+    fn exit_code_survives_context() {
+        let result: Result<()> = Err(ErrorKind::Command.error("git failed"));
+        let error = result
+            .context("Failed to read log")
+            .context("Failed to generate changelog")
+            .unwrap_err();
+        assert_eq!(exit_code(&error), 4);
+        let result: Result<()> = Err(anyhow!("bad"));
+        let error = result.context("Failed").unwrap_err();
+        assert_eq!(exit_code(&error), 1);
+    }
+
+    #[test]
+    fn usage_errors_are_input_errors() {
+        let opts: Opts = parse_args_from(&["tool", "--count", "3"]).unwrap();
+        assert_eq!(opts.count, 3);
+        for args in &[
+            &["tool", "--count", "three"][..],
+            &["tool"][..],
+            &["tool", "--count", "3", "--unknown"][..],
+        ] {
+            let error = parse_args_from::<Opts, _>(*args).unwrap_err();
+            assert_eq!(exit_code(&error), 2);
+        }
     }
 
-    let text = str::from_utf8(&output.stdout)?.trim_end().to_string();
-    Ok(text)
+    #[test]
+    fn decode_output_replaces_invalid_utf8_unless_strict() {
+        let bytes = b"caf\xe9".to_vec();
+        assert_eq!(decode_output(bytes.clone(), false).unwrap(), "caf\u{fffd}");
+        assert!(decode_output(bytes, true).is_err());
+        assert_eq!(decode_output(b"ok".to_vec(), true).unwrap(), "ok");
+    }
+
+    /// Return a command failing until it is run for the third time
+    fn third_time_lucky(counter: &Path) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg("echo >> \"$1\" && [ $(wc -l < \"$1\") -ge 3 ] && echo done")
+            .arg("sh")
+            .arg(counter);
+        command
+    }
+
+    fn run_count(counter: &Path) -> usize {
+        std::fs::read_to_string(counter).unwrap().lines().count()
+    }
+
+    #[test]
+    fn retry_until_command_succeeds() {
+        let dir = tempdir().unwrap();
+        let counter = dir.path().join("count");
+        let mut command = third_time_lucky(&counter);
+        let text =
+            run_command_with_retry(&mut command, "failed", 3, Duration::ZERO)
+                .unwrap();
+        assert_eq!(text, "done");
+        assert_eq!(run_count(&counter), 3);
+
+        let counter = dir.path().join("count-2");
+        let mut command = third_time_lucky(&counter);
+        let e =
+            run_command_with_retry(&mut command, "failed", 2, Duration::ZERO)
+                .unwrap_err();
+        assert_eq!(exit_code(&e), 4);
+        assert_eq!(run_count(&counter), 2);
+    }
+
+    #[test]
+    fn retry_runs_command_at_least_once() {
+        let dir = tempdir().unwrap();
+        for attempts in 0..2 {
+            let counter = dir.path().join(format!("count-{}", attempts));
+            let mut command = third_time_lucky(&counter);
+            let result = run_command_with_retry(
+                &mut command,
+                "failed",
+                attempts,
+                Duration::ZERO,
+            );
+            assert!(result.is_err());
+            assert_eq!(run_count(&counter), 1);
+        }
+    }
+
+    #[test]
+    fn retry_skips_commands_failing_to_start() {
+        let mut command = Command::new("/nonexistent/command");
+        let backoff = Duration::from_secs(60);
+        let start = Instant::now();
+        let e = run_command_with_retry(&mut command, "failed", 3, backoff)
+            .unwrap_err();
+        assert!(start.elapsed() < backoff);
+        assert_eq!(exit_code(&e), 4);
+        assert!(e.to_string().starts_with("failed ("));
+    }
 }
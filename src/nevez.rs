@@ -6,24 +6,37 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 //
 
-use anyhow::Result;
-use chrono::{DateTime, FixedOffset, Utc};
-use elb_dev_tools_ng::run_command_or;
+//! Generate a changelog
+
+use crate::changelog::HeadingFormat;
+use crate::{decode_output, git, ErrorKind};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, FixedOffset, NaiveDate, TimeZone, Utc};
+use handlebars::{no_escape, to_json, Handlebars, JsonValue};
 use regex::Regex;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsString;
-use std::fs::{rename, File};
-use std::io::{stdout, BufRead, BufReader, Write};
+use std::fs::{self, rename, File};
+use std::io::{stdout, BufRead, BufReader, Split, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::str;
+use std::process::{Child, ChildStdout};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Nevez", about = "Generate a changelog")]
-struct NevezOptions {
+pub struct NevezOptions {
     #[structopt(short = "-s", long = "--since", help = "previous tag")]
     old_tag: Option<String>,
 
+    #[structopt(
+        long = "since-date",
+        help = "Only consider commits more recent than date",
+        value_name = "YYYY-MM-DD",
+        parse(try_from_str = parse_date)
+    )]
+    since_date: Option<NaiveDate>,
+
     #[structopt(
         short = "f",
         long = "file",
@@ -33,6 +46,16 @@ struct NevezOptions {
     )]
     changelog: PathBuf,
 
+    #[structopt(
+        long = "heading-format",
+        help = "Changelog headings: keepachangelog, paren, or a template \
+                with {version} and {date}",
+        default_value = "keepachangelog",
+        value_name = "FORMAT",
+        parse(try_from_str = parse_heading_format)
+    )]
+    heading_format: HeadingFormat,
+
     #[structopt(
         short = "i",
         long = "in-place",
@@ -40,11 +63,148 @@ struct NevezOptions {
     )]
     in_place: bool,
 
-    #[structopt(help = "New tag")]
-    new_tag: String,
+    #[structopt(
+        short = "o",
+        long = "out",
+        help = "Write updated changelog to another file",
+        value_name = "PATH",
+        conflicts_with = "in-place",
+        parse(from_os_str)
+    )]
+    out: Option<PathBuf>,
+
+    #[structopt(
+        short = "t",
+        long = "template",
+        help = "Path to section template",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    template: Option<PathBuf>,
+
+    #[structopt(
+        short = "a",
+        long = "age",
+        help = "Note time elapsed since previous tag"
+    )]
+    age: bool,
+
+    #[structopt(
+        long = "tags-mode",
+        help = "Tags to consider",
+        possible_values = &["annotated", "all"],
+        default_value = "all",
+        value_name = "MODE"
+    )]
+    tags_mode: String,
+
+    #[structopt(
+        long = "tag-match",
+        help = "Only consider tags matching glob",
+        value_name = "PATTERN"
+    )]
+    tag_match: Option<String>,
+
+    #[structopt(
+        long = "no-bump-filter",
+        help = "List version bumps as changes"
+    )]
+    no_bump_filter: bool,
+
+    #[structopt(long = "include-merges", help = "List merge commits too")]
+    include_merges: bool,
+
+    #[structopt(
+        long = "first-parent",
+        help = "Only follow first parent of merge commits"
+    )]
+    first_parent: bool,
+
+    #[structopt(
+        long = "exclude-path",
+        help = "Skip commits only touching paths matching pattern",
+        number_of_values = 1,
+        value_name = "PATTERN"
+    )]
+    exclude_paths: Vec<String>,
+
+    #[structopt(
+        long = "counts",
+        help = "Show number of commits per category in header"
+    )]
+    counts: bool,
+
+    #[structopt(
+        long = "count",
+        help = "Only print number of commits per category"
+    )]
+    count: bool,
+
+    #[structopt(
+        long = "format",
+        help = "Output format",
+        possible_values = &["markdown", "jsonl"],
+        default_value = "markdown",
+        value_name = "FORMAT"
+    )]
+    format: String,
+
+    #[structopt(
+        long = "catch-all",
+        help = "List commits without subject as changes"
+    )]
+    catch_all: bool,
+
+    #[structopt(
+        long = "anchor",
+        help = "Insert new section after this line if present",
+        default_value = "<!-- nevez:insert -->",
+        value_name = "MARKER"
+    )]
+    anchor: String,
+
+    #[structopt(
+        long = "fail-on-empty",
+        help = "Fail if there is no commit to list"
+    )]
+    fail_on_empty: bool,
+
+    #[structopt(
+        long = "show-commands",
+        help = "Print git commands before running them"
+    )]
+    show_commands: bool,
+
+    #[structopt(
+        long = "strict-utf8",
+        help = "Fail on git output that is not valid UTF-8"
+    )]
+    strict_utf8: bool,
+
+    #[structopt(
+        long = "auto-tag",
+        help = "Take previous tag, and new tag if not given, from repository"
+    )]
+    auto_tag: bool,
+
+    #[structopt(
+        help = "New tag, optional with --auto-tag",
+        required_unless = "auto-tag"
+    )]
+    new_tag: Option<String>,
 
     #[structopt(help = "Repository")]
     repository: Option<PathBuf>,
+
+    #[structopt(
+        short = "C",
+        long = "repository",
+        help = "Repository, when no new tag is given with --auto-tag",
+        value_name = "DIR",
+        conflicts_with = "repository",
+        parse(from_os_str)
+    )]
+    repository_dir: Option<PathBuf>,
 }
 
 /// Represent the author of a commit
@@ -66,50 +226,128 @@ struct Commit {
 impl Commit {
     /// Return first line of commit message
     fn brief(&self) -> Option<&str> {
-        self.message.lines().nth(0)
+        self.message.lines().nth(0).filter(|l| !l.trim().is_empty())
+    }
+
+    /// Return abbreviated commit identifier
+    fn short_id(&self) -> &str {
+        self.id.get(..7).unwrap_or(&self.id)
     }
 }
 
-/// Parse a `git log` commit
+/// Format of `git log` records: NUL-separated fields, ended by RS
+const LOG_FORMAT: &str = "%H%x00%an%x00%ae%x00%aI%x00%B%x1e";
+
+/// Separator of fields in a `git log` record
+const LOG_FIELD_SEPARATOR: char = '\x00';
+
+/// Separator of `git log` records
+const LOG_RECORD_SEPARATOR: char = '\x1e';
+
+/// Parse a `git log` commit, formatted with `LOG_FORMAT`
 #[derive(Debug)]
-struct CommitLogParser {
-    pat_author: Regex,
-    pat_date: Regex,
-}
+struct CommitLogParser;
 
 impl CommitLogParser {
     /// Create a new commit log parser
-    fn new() -> Result<Self> {
-        let pat_author = Regex::new(r"^Author:\s+(.+)<(.+)>$")?;
-        let pat_date = Regex::new(r"^Date:\s+(.+)$")?;
-        Ok(CommitLogParser {
-            pat_author,
-            pat_date,
-        })
+    fn new() -> Self {
+        CommitLogParser
     }
 
-    /// Parse commit log
-    fn parse(&self, text: &str) -> Option<Commit> {
-        let mut lines = text.lines();
-        let id = lines.nth(0)?;
-        let caps = lines.next().and_then(|l| self.pat_author.captures(l))?;
+    /// Parse commit log record
+    fn parse(&self, record: &str) -> Option<Commit> {
+        let record = record.trim_start_matches('\n');
+        let mut fields = record.splitn(5, LOG_FIELD_SEPARATOR);
+        let id = fields.next()?;
         let author = Author {
-            name: caps[1].to_string(),
-            email: caps[2].to_string(),
+            name: fields.next()?.trim().to_string(),
+            email: fields.next()?.trim().to_string(),
         };
-        let caps = lines.next().and_then(|l| self.pat_date.captures(l))?;
-        let date = DateTime::parse_from_rfc2822(&caps[1]).ok()?;
+        let date = DateTime::parse_from_rfc3339(fields.next()?).ok()?;
         Some(Commit {
             id: id.to_string(),
-            author: author,
-            date: date,
-            message: lines
-                .skip(1)
-                .map(str::trim_start)
-                .collect::<Vec<&str>>()
-                .join("\n"),
+            author,
+            date,
+            message: fields.next()?.trim_end().to_string(),
+        })
+    }
+}
+
+/// Stream commits from the output of `git log`
+struct CommitStream {
+    child: Child,
+    records: Split<BufReader<ChildStdout>>,
+    parser: CommitLogParser,
+    strict_utf8: bool,
+}
+
+impl CommitStream {
+    /// Start reading commits since `tag` and/or the date of `settings`
+    fn new(
+        git: &Git,
+        tag: Option<&str>,
+        settings: &ChangelogSettings,
+    ) -> Result<Self> {
+        let until = settings.until.as_deref().unwrap_or("HEAD");
+        let range = match tag {
+            Some(tag) => format!("{}..{}", tag, until),
+            None => until.to_string(),
+        };
+        let options = git::LogOptions {
+            format: Some(LOG_FORMAT.to_string()),
+            no_merges: !settings.include_merges,
+            exclude_grep: Some("^Squash".to_string()),
+            since: settings
+                .since_date
+                .map(|date| date.format("%Y-%m-%d").to_string()),
+            first_parent: settings.first_parent,
+            exclude_paths: settings.exclude_paths.clone(),
+        };
+        let mut child =
+            git::spawn_log(&git.repo, &git.options, &range, &options)?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| ErrorKind::Command.error("git-log failed"))?;
+        Ok(CommitStream {
+            child,
+            records: BufReader::new(stdout).split(LOG_RECORD_SEPARATOR as u8),
+            parser: CommitLogParser::new(),
+            strict_utf8: git.options.strict_utf8,
         })
     }
+
+    /// Wait for `git log` to exit, checking it succeeded
+    fn finish(mut self) -> Result<()> {
+        if !self.child.wait()?.success() {
+            return Err(ErrorKind::Command.error("git-log failed"));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for CommitStream {
+    type Item = Result<Commit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for record in &mut self.records {
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e.into())),
+            };
+            let record = match decode_output(record, self.strict_utf8) {
+                Ok(record) => record,
+                Err(e) => return Some(Err(e)),
+            };
+            if record.trim().is_empty() {
+                continue;
+            }
+            if let Some(commit) = self.parser.parse(&record) {
+                return Some(Ok(commit));
+            }
+        }
+        None
+    }
 }
 
 /// Kind of commits
@@ -117,7 +355,37 @@ impl CommitLogParser {
 enum CommitKind {
     Addition,
     Bump,
+    Deprecation,
     Fix,
+    Removal,
+    Security,
+}
+
+/// Category of commits in a changelog
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Category {
+    Added,
+    Changed,
+    Deprecated,
+    Removed,
+    Fixed,
+    Security,
+    Ignored,
+}
+
+impl Category {
+    /// Return the name of the category
+    fn name(self) -> &'static str {
+        match self {
+            Category::Added => "added",
+            Category::Changed => "changed",
+            Category::Deprecated => "deprecated",
+            Category::Removed => "removed",
+            Category::Fixed => "fixed",
+            Category::Security => "security",
+            Category::Ignored => "ignored",
+        }
+    }
 }
 
 /// Classify commits
@@ -126,14 +394,37 @@ struct CommitClassifier {
     add_patterns: Vec<Regex>,
     fix_patterns: Vec<Regex>,
     bump_patterns: Vec<Regex>,
+    deprecation_patterns: Vec<Regex>,
+    removal_patterns: Vec<Regex>,
+    security_patterns: Vec<Regex>,
+    keep_bumps: bool,
 }
 
 /// Result of classification
+///
+/// Each commit lands in exactly one of the categories.
 #[derive(Debug)]
 struct ClassifiedCommits<'a> {
     additions: Vec<&'a Commit>,
     changes: Vec<&'a Commit>,
+    deprecations: Vec<&'a Commit>,
+    removals: Vec<&'a Commit>,
     fixes: Vec<&'a Commit>,
+    security: Vec<&'a Commit>,
+    /// Version bumps, not listed in the changelog
+    ignored: Vec<&'a Commit>,
+}
+
+impl ClassifiedCommits<'_> {
+    /// Check if there is no commit in any listed category
+    fn is_empty(&self) -> bool {
+        self.additions.is_empty()
+            && self.changes.is_empty()
+            && self.deprecations.is_empty()
+            && self.removals.is_empty()
+            && self.fixes.is_empty()
+            && self.security.is_empty()
+    }
 }
 
 impl CommitClassifier {
@@ -158,46 +449,88 @@ impl CommitClassifier {
             )?,
             Regex::new(r"^(version|VERSION):\s+[Bb]ump(?:ed)?.+$")?,
         ];
+        let deprecation_patterns = vec![Regex::new(r"^[Dd]eprecate")?];
+        let removal_patterns = vec![Regex::new(r"^[Rr]emove")?];
+        let security_patterns = vec![Regex::new(r"^[Ss]ecurity|CVE")?];
 
         Ok(CommitClassifier {
             add_patterns,
             fix_patterns,
             bump_patterns,
+            deprecation_patterns,
+            removal_patterns,
+            security_patterns,
+            keep_bumps: false,
         })
     }
 
+    /// Keep version bump commits as changes instead of dropping them
+    fn set_keep_bumps(&mut self, keep_bumps: bool) {
+        self.keep_bumps = keep_bumps;
+    }
+
     /// Check kind of commit by looking at its message
     fn check_kind(&self, kind: CommitKind, message: &str) -> bool {
         let patterns = match kind {
             CommitKind::Addition => &self.add_patterns,
             CommitKind::Bump => &self.bump_patterns,
+            CommitKind::Deprecation => &self.deprecation_patterns,
             CommitKind::Fix => &self.fix_patterns,
+            CommitKind::Removal => &self.removal_patterns,
+            CommitKind::Security => &self.security_patterns,
         };
-        patterns.iter().any({ |p| p.is_match(message) })
+        patterns.iter().any(|p| p.is_match(message))
+    }
+
+    /// Return the category of a commit
+    ///
+    /// Security fixes are picked first, then deprecations, removals,
+    /// additions and fixes. Remaining commits are changes, except version
+    /// bumps which are ignored unless kept. Commits without subject match no
+    /// pattern, so they are changes.
+    fn category(&self, commit: &Commit) -> Category {
+        let is_kind =
+            |kind| commit.brief().is_some_and(|m| self.check_kind(kind, m));
+        if is_kind(CommitKind::Security) {
+            Category::Security
+        } else if is_kind(CommitKind::Deprecation) {
+            Category::Deprecated
+        } else if is_kind(CommitKind::Removal) {
+            Category::Removed
+        } else if is_kind(CommitKind::Addition) {
+            Category::Added
+        } else if is_kind(CommitKind::Fix) {
+            Category::Fixed
+        } else if !self.keep_bumps && is_kind(CommitKind::Bump) {
+            Category::Ignored
+        } else {
+            Category::Changed
+        }
     }
 
     /// Perform classification
     fn classify<'a>(&self, commits: &'a [Commit]) -> ClassifiedCommits<'a> {
-        let (additions, others): (Vec<&'a Commit>, Vec<&'a Commit>) =
-            commits.iter().partition(|&c| {
-                c.brief()
-                    .map_or(false, |m| self.check_kind(CommitKind::Addition, m))
-            });
-        let (fixes, others): (Vec<&'a Commit>, Vec<&'a Commit>) =
-            others.iter().partition(|&c| {
-                c.brief()
-                    .map_or(false, |m| self.check_kind(CommitKind::Fix, m))
-            });
-        let (_, changes): (Vec<&'a Commit>, Vec<&'a Commit>) =
-            others.iter().partition(|&c| {
-                c.brief()
-                    .map_or(false, |m| self.check_kind(CommitKind::Bump, m))
-            });
-        ClassifiedCommits {
-            additions,
-            changes,
-            fixes,
+        let mut classified = ClassifiedCommits {
+            additions: Vec::new(),
+            changes: Vec::new(),
+            deprecations: Vec::new(),
+            removals: Vec::new(),
+            fixes: Vec::new(),
+            security: Vec::new(),
+            ignored: Vec::new(),
+        };
+        for commit in commits {
+            match self.category(commit) {
+                Category::Added => classified.additions.push(commit),
+                Category::Changed => classified.changes.push(commit),
+                Category::Deprecated => classified.deprecations.push(commit),
+                Category::Removed => classified.removals.push(commit),
+                Category::Fixed => classified.fixes.push(commit),
+                Category::Security => classified.security.push(commit),
+                Category::Ignored => classified.ignored.push(commit),
+            }
         }
+        classified
     }
 }
 
@@ -253,135 +586,1188 @@ fn format_md_section(level: usize, title: &str, items: &[String]) -> String {
     text
 }
 
+/// Render a changelog section using a handlebars template
+fn format_template(
+    template: &str,
+    data: &BTreeMap<&str, JsonValue>,
+) -> Result<String> {
+    let mut handlebars = Handlebars::new();
+    handlebars.register_escape_fn(no_escape);
+    handlebars.register_template_string("t", template)?;
+    let text = handlebars.render("t", data)?;
+    Ok(text)
+}
+
+/// Describe a duration in a human-friendly way
+fn format_age(age: Duration) -> String {
+    let days = age.num_days();
+    match days {
+        d if d < 1 => "less than a day".to_string(),
+        1 => "1 day".to_string(),
+        d if d < 14 => format!("{} days", d),
+        d if d < 60 => format!("{} weeks", d / 7),
+        d if d < 730 => format!("{} months", d / 30),
+        d => format!("{} years", d / 365),
+    }
+}
+
 #[derive(Debug)]
 struct Formatter {
     shortener: CommitShortener,
+    heading: HeadingFormat,
+    template: Option<String>,
+    age: Option<String>,
+    counts: bool,
+    catch_all: bool,
 }
 
 impl Formatter {
     /// Create a new formatter, with a shortener
     fn new(shortener: CommitShortener) -> Self {
-        Formatter { shortener }
+        Formatter {
+            shortener,
+            heading: HeadingFormat::default(),
+            template: None,
+            age: None,
+            counts: false,
+            catch_all: false,
+        }
+    }
+
+    /// Set the format of the version heading
+    fn set_heading(&mut self, heading: &HeadingFormat) {
+        self.heading = heading.clone();
+    }
+
+    /// Use a handlebars template instead of the built-in Markdown layout
+    fn set_template(&mut self, template: &str) {
+        self.template = Some(template.to_string());
+    }
+
+    /// Note the time elapsed since the previous release
+    fn set_age(&mut self, age: &str) {
+        self.age = Some(age.to_string());
+    }
+
+    /// Append the number of commits of each category to the header
+    fn set_counts(&mut self, counts: bool) {
+        self.counts = counts;
+    }
+
+    /// List commits without subject by their identifier instead of
+    /// dropping them
+    fn set_catch_all(&mut self, catch_all: bool) {
+        self.catch_all = catch_all;
     }
 
     /// Format commits as changelog snippet
-    fn format(&self, commits: &ClassifiedCommits, tag: &str) -> String {
+    fn format(&self, commits: &ClassifiedCommits, tag: &str) -> Result<String> {
         let additions = self.shorten(&commits.additions);
         let changes = self.shorten(&commits.changes);
+        let deprecations = self.shorten(&commits.deprecations);
+        let removals = self.shorten(&commits.removals);
         let fixes = self.shorten(&commits.fixes);
+        let security = self.shorten(&commits.security);
         let timestamp: DateTime<Utc> = Utc::now();
-        let mut text =
-            format!("## [{}] - {}\n", tag, timestamp.format("%Y-%m-%d"));
+        let date = timestamp.format("%Y-%m-%d").to_string();
+        if let Some(template) = self.template.as_ref() {
+            let mut data = BTreeMap::new();
+            data.insert("tag", to_json(tag));
+            data.insert("date", to_json(&date));
+            data.insert("age", to_json(&self.age));
+            data.insert("added", to_json(&additions));
+            data.insert("changed", to_json(&changes));
+            data.insert("deprecated", to_json(&deprecations));
+            data.insert("removed", to_json(&removals));
+            data.insert("fixed", to_json(&fixes));
+            data.insert("security", to_json(&security));
+            return format_template(template, &data);
+        }
+        let mut text = self.heading.heading(tag, &date)?;
+        if self.counts {
+            let mut counts = vec![
+                format!("{} added", commits.additions.len()),
+                format!("{} changed", commits.changes.len()),
+                format!("{} fixed", commits.fixes.len()),
+            ];
+            let others = [
+                ("deprecated", commits.deprecations.len()),
+                ("removed", commits.removals.len()),
+                ("security", commits.security.len()),
+            ];
+            for (name, count) in others.iter().filter(|(_, n)| *n > 0) {
+                counts.push(format!("{} {}", count, name));
+            }
+            text.push_str(&format!(" ({})", counts.join(", ")));
+        }
+        text.push('\n');
+        if let Some(age) = self.age.as_ref() {
+            text.push_str(&format!("<!-- {} -->\n", age));
+        }
         text.push_str(&format_md_section(3, "Added", &additions));
         text.push_str(&format_md_section(3, "Changed", &changes));
+        text.push_str(&format_md_section(3, "Deprecated", &deprecations));
+        text.push_str(&format_md_section(3, "Removed", &removals));
         text.push_str(&format_md_section(3, "Fixed", &fixes));
-        text
+        text.push_str(&format_md_section(3, "Security", &security));
+        Ok(text)
     }
 
     fn shorten(&self, commits: &[&Commit]) -> Vec<String> {
         let mut commits: Vec<String> = commits
             .iter()
-            .filter_map(|&c| self.shortener.shorten(c))
+            .filter_map(|&c| {
+                self.shortener.shorten(c).or_else(|| {
+                    if self.catch_all {
+                        Some(format!("{} (no subject)", c.short_id()))
+                    } else {
+                        None
+                    }
+                })
+            })
             .collect();
         commits.sort();
         commits
     }
 }
 
-/// Collect commits since `tag` in repository at `path`
-fn collect_commits<P: AsRef<Path>>(path: P, tag: &str) -> Result<Vec<Commit>> {
-    let mut cmd = Command::new("git");
-    cmd.arg("--git-dir")
-        .arg(path.as_ref())
-        .arg("log")
-        .arg("--date=rfc2822")
-        .arg("--no-merges")
-        .arg("--invert-grep")
-        .arg("--grep")
-        .arg("^Squash")
-        .arg(format!("{}..HEAD", tag));
-
-    let text = run_command_or(&mut cmd, "git-log failed")?;
-    let parser = CommitLogParser::new()?;
-    let pattern = Regex::new(r"(?x)commit ")?;
-    let commits = pattern
-        .split(&text)
-        .skip(1)
-        .filter_map(|commit| parser.parse(commit))
-        .collect();
+/// Run git commands on a repository, memoizing their output
+#[derive(Debug)]
+struct Git {
+    repo: PathBuf,
+    options: git::Options,
+    cache: RefCell<HashMap<Vec<String>, String>>,
+}
+
+impl Git {
+    /// Create a new git runner for repository at `repo`
+    fn new<P: AsRef<Path>>(repo: P) -> Self {
+        Git {
+            repo: PathBuf::from(repo.as_ref()),
+            options: git::Options::default(),
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Set the options of the git commands
+    fn set_options(&mut self, options: git::Options) {
+        self.options = options;
+    }
+
+    /// Run git with `args`, reusing the output of an identical previous run
+    fn run(&self, args: &[&str], error: &str) -> Result<String> {
+        let key: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        if let Some(text) = self.cache.borrow().get(&key) {
+            return Ok(text.clone());
+        }
+        let text = git::run(&self.repo, &self.options, args, error)?;
+        self.cache.borrow_mut().insert(key, text.clone());
+        Ok(text)
+    }
+}
+
+/// Collect commits since `tag` and/or `date`, merge commits included if
+/// `merges` is set
+fn collect_commits(
+    git: &Git,
+    tag: Option<&str>,
+    settings: &ChangelogSettings,
+) -> Result<Vec<Commit>> {
+    let mut stream = CommitStream::new(git, tag, settings)?;
+    let commits = stream.by_ref().collect::<Result<Vec<Commit>>>()?;
+    stream.finish()?;
     Ok(commits)
 }
 
-/// Find the latest annotated tag
-fn find_latest_tag<P: AsRef<Path>>(path: P) -> Result<String> {
-    let mut cmd = Command::new("git");
-    cmd.arg("--git-dir")
-        .arg(path.as_ref())
-        .arg("describe")
-        .arg("--abbrev=0")
-        .arg("--tags");
-    run_command_or(&mut cmd, "git-describe failed")
+/// Find the date of the commit pointed to by `rev`
+fn find_rev_date(git: &Git, rev: &str) -> Result<DateTime<FixedOffset>> {
+    let text =
+        git.run(&["log", "-1", "--format=%cI", rev], "git-log failed")?;
+    let date = DateTime::parse_from_rfc3339(&text)?;
+    Ok(date)
 }
 
-/// Generate a changelog
-fn generate_changelog<P: AsRef<Path>>(
-    repository: P,
-    old_tag: &str,
-    new_tag: &str,
+/// Parse a date given as YYYY-MM-DD
+fn parse_date(date: &str) -> Result<NaiveDate> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| ErrorKind::Input.error(format!("Invalid date {}", date)))
+}
+
+/// Parse a heading format able to generate the heading of the new release
+fn parse_heading_format(format: &str) -> Result<HeadingFormat> {
+    let heading = HeadingFormat::new(format)?;
+    if !heading.can_generate() {
+        return Err(ErrorKind::Input.error(format!(
+            "Heading format {} can not generate headings",
+            format
+        )));
+    }
+    Ok(heading)
+}
+
+/// Return the time at midnight UTC of `date`
+fn midnight(date: NaiveDate) -> Result<DateTime<FixedOffset>> {
+    let midnight = date.and_hms_opt(0, 0, 0).context("Invalid time")?;
+    Ok(Utc.from_utc_datetime(&midnight).into())
+}
+
+/// Settings of changelog generation
+#[derive(Debug, Default)]
+struct ChangelogSettings {
+    heading: HeadingFormat,
+    template: Option<String>,
+    since_date: Option<NaiveDate>,
+    until: Option<String>,
+    age: bool,
+    keep_bumps: bool,
+    include_merges: bool,
+    first_parent: bool,
+    exclude_paths: Vec<String>,
+    counts: bool,
+    catch_all: bool,
+}
+
+/// Write one JSON object per commit on stdout, as commits are read
+fn write_commits_jsonl(
+    git: &Git,
+    old_tag: Option<&str>,
+    settings: &ChangelogSettings,
+) -> Result<()> {
+    let mut classifier = CommitClassifier::new()?;
+    classifier.set_keep_bumps(settings.keep_bumps);
+    let mut stream = CommitStream::new(git, old_tag, settings)?;
+    let stdout = stdout();
+    let mut out = stdout.lock();
+    for commit in stream.by_ref() {
+        let commit = commit?;
+        let author =
+            format!("{} <{}>", commit.author.name, commit.author.email);
+        let mut data = BTreeMap::new();
+        data.insert("id", to_json(&commit.id));
+        data.insert("author", to_json(&author));
+        data.insert("date", to_json(commit.date.to_rfc3339()));
+        data.insert("category", to_json(classifier.category(&commit).name()));
+        data.insert("brief", to_json(commit.brief().unwrap_or("")));
+        writeln!(out, "{}", to_json(&data))?;
+    }
+    stream.finish()
+}
+
+/// Summarize the number of commits per category
+fn count_changes(
+    git: &Git,
+    old_tag: Option<&str>,
+    settings: &ChangelogSettings,
 ) -> Result<String> {
-    let commits = collect_commits(repository, old_tag)?;
-    let classifier = CommitClassifier::new()?;
+    let commits = collect_commits(git, old_tag, settings)?;
+    let mut classifier = CommitClassifier::new()?;
+    classifier.set_keep_bumps(settings.keep_bumps);
+    let commits = classifier.classify(&commits);
+    let added = commits.additions.len();
+    let changed = commits.changes.len();
+    let fixed = commits.fixes.len();
+    let deprecated = commits.deprecations.len();
+    let removed = commits.removals.len();
+    let security = commits.security.len();
+    Ok(format!(
+        "added={} changed={} deprecated={} removed={} fixed={} security={} \
+         total={}",
+        added,
+        changed,
+        deprecated,
+        removed,
+        fixed,
+        security,
+        added + changed + deprecated + removed + fixed + security
+    ))
+}
+
+/// Generate a changelog, if there are commits to list
+fn generate_changelog(
+    git: &Git,
+    old_tag: Option<&str>,
+    new_tag: &str,
+    settings: &ChangelogSettings,
+) -> Result<Option<String>> {
+    let commits = collect_commits(git, old_tag, settings)?;
+    let mut classifier = CommitClassifier::new()?;
+    classifier.set_keep_bumps(settings.keep_bumps);
     let commits = classifier.classify(&commits);
+    if commits.is_empty() {
+        return Ok(None);
+    }
     let shortener = CommitShortener::new()?;
-    let formatter = Formatter::new(shortener);
-    let text = formatter.format(&commits, new_tag);
-    Ok(text)
+    let mut formatter = Formatter::new(shortener);
+    formatter.set_heading(&settings.heading);
+    formatter.set_counts(settings.counts);
+    formatter.set_catch_all(settings.catch_all);
+    if let Some(template) = settings.template.as_ref() {
+        formatter.set_template(template);
+    }
+    if settings.age {
+        let (since, origin) = match (old_tag, settings.since_date) {
+            (Some(tag), _) => (find_rev_date(git, tag)?, tag.to_string()),
+            (None, Some(date)) => (midnight(date)?, date.to_string()),
+            (None, None) => {
+                return Err(
+                    ErrorKind::Input.error("No tag nor date to start from")
+                )
+            }
+        };
+        let until =
+            find_rev_date(git, new_tag).unwrap_or_else(|_| Utc::now().into());
+        let age = format!("{} since {}", format_age(until - since), origin);
+        formatter.set_age(&age);
+    }
+    formatter.format(&commits, new_tag).map(Some)
+}
+
+/// Where the updated changelog is written
+#[derive(Debug, Clone, Copy)]
+enum Destination<'a> {
+    /// Standard output
+    Stdout,
+    /// The changelog itself
+    InPlace,
+    /// Another file, leaving the changelog untouched
+    File(&'a Path),
 }
 
 /// Update a changelog
+///
+/// The new section is inserted after the `anchor` line and a blank line
+/// if there is one, before the first version heading otherwise. The line
+/// endings of the changelog and the presence of its final newline are
+/// preserved.
 fn update_changelog<P: AsRef<Path>>(
     changelog: P,
     text: &str,
-    in_place: bool,
+    anchor: &str,
+    heading: &HeadingFormat,
+    destination: Destination,
 ) -> Result<()> {
     let mut inserted = false;
-    let pat = Regex::new(r"^##\s+\[[\w.]+\]\s+-\s+[\d]{4}-[\d]{2}-[\d]{2}$")?;
-    let input = File::open(&changelog)?;
-    let reader = BufReader::new(input);
-    let mut tmp = OsString::from(&changelog.as_ref());
-    tmp.push(".tmp");
-    let mut writer: Box<dyn Write> = match in_place {
-        true => {
-            let file = File::create(&tmp)?;
-            Box::new(file)
-        }
-        false => Box::new(stdout()),
+    let content = fs::read_to_string(&changelog).map_err(|e| {
+        ErrorKind::Input.error(format!(
+            "Failed to read {} ({})",
+            changelog.as_ref().display(),
+            e
+        ))
+    })?;
+    let has_anchor = content.lines().any(|line| line.trim() == anchor);
+    let eol = if content.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
     };
-    for line in reader.lines() {
-        let line = line?;
-        if pat.is_match(&line) {
-            if !inserted {
-                write!(writer, "{}", text)?;
-                inserted = true;
+    let text = text.replace('\n', eol);
+    let mut writer = Vec::new();
+    let mut lines = content.lines().peekable();
+    while let Some(line) = lines.next() {
+        if !has_anchor && !inserted && heading.is_release(line) {
+            write!(writer, "{}", text)?;
+            inserted = true;
+        }
+        let is_anchor = has_anchor && !inserted && line.trim() == anchor;
+        write!(writer, "{}", line)?;
+        if lines.peek().is_some() || content.ends_with('\n') || is_anchor {
+            write!(writer, "{}", eol)?;
+        }
+        if is_anchor {
+            // The section already ends with a blank line
+            write!(writer, "{}{}", eol, text)?;
+            while lines.peek().is_some_and(|line| line.trim().is_empty()) {
+                lines.next();
             }
+            inserted = true;
         }
-        write!(writer, "{}\n", line)?;
     }
-    if in_place {
-        rename(&tmp, &changelog)?;
+    match destination {
+        Destination::Stdout => stdout().write_all(&writer)?,
+        Destination::InPlace => replace_file(changelog.as_ref(), &writer)?,
+        Destination::File(path) => replace_file(path, &writer)?,
     }
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let opts = NevezOptions::from_args();
+/// Write `data` to `path` through a temporary file renamed over it
+fn replace_file(path: &Path, data: &[u8]) -> Result<()> {
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    rename(&tmp, path)?;
+    sync_parent_dir(path)
+}
+
+/// Flush the directory holding `path` to disk, so that a rename persists
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+/// Flush the directory holding `path` to disk, so that a rename persists
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Run nevez with options `opts`
+pub fn run(opts: NevezOptions) -> Result<()> {
     let cwd = std::env::current_dir()?;
-    let repo = opts.repository.unwrap_or(cwd);
-    let gitdir = repo.join(".git");
-    let last_tag = find_latest_tag(&gitdir)?;
-    let old_tag = opts.old_tag.unwrap_or(last_tag);
-    let text = generate_changelog(gitdir, &old_tag, &opts.new_tag)?;
+    let repo = opts.repository_dir.or(opts.repository).unwrap_or(cwd);
+    let options = git::Options {
+        show_commands: opts.show_commands,
+        strict_utf8: opts.strict_utf8,
+    };
+    let mut git = Git::new(&repo);
+    git.set_options(options);
+    let all_tags = opts.tags_mode == "all";
+    let tag_match = opts.tag_match.as_deref();
+    let (new_tag, until) = match opts.new_tag {
+        Some(tag) => (tag, None),
+        None => {
+            let tag = git::latest_tag(&repo, &options, all_tags, tag_match)
+                .map_err(|_| {
+                    ErrorKind::Validation
+                        .error("No tag found to use as new tag")
+                })?;
+            (tag.clone(), Some(tag))
+        }
+    };
+    let old_tag = match (opts.old_tag, opts.since_date.as_ref()) {
+        (Some(tag), _) => Some(tag),
+        (None, Some(_)) => None,
+        (None, None)
+            if opts.auto_tag
+                && (until.is_some()
+                    || git::tag_exists(&repo, &options, &new_tag)?) =>
+        {
+            git::previous_tag(&repo, &options, &new_tag, all_tags, tag_match)?
+        }
+        (None, None) => {
+            Some(git::latest_tag(&repo, &options, all_tags, tag_match)?)
+        }
+    };
+    let template = match opts.template {
+        Some(path) => Some(fs::read_to_string(path).map_err(|e| {
+            ErrorKind::Input.error(format!("Failed to read template ({})", e))
+        })?),
+        None => None,
+    };
+    let settings = ChangelogSettings {
+        heading: opts.heading_format,
+        template,
+        since_date: opts.since_date,
+        until,
+        age: opts.age,
+        keep_bumps: opts.no_bump_filter,
+        include_merges: opts.include_merges,
+        first_parent: opts.first_parent,
+        exclude_paths: opts.exclude_paths,
+        counts: opts.counts,
+        catch_all: opts.catch_all,
+    };
+    if opts.format == "jsonl" {
+        return write_commits_jsonl(&git, old_tag.as_deref(), &settings);
+    }
+    if opts.count {
+        println!("{}", count_changes(&git, old_tag.as_deref(), &settings)?);
+        return Ok(());
+    }
+    let text =
+        generate_changelog(&git, old_tag.as_deref(), &new_tag, &settings)?;
+    let text = match text {
+        Some(text) => text,
+        None if opts.fail_on_empty => {
+            return Err(ErrorKind::Validation
+                .error(format!("No commits to list for {}", new_tag)))
+        }
+        None => {
+            eprintln!("No commits to list, changelog left untouched");
+            return Ok(());
+        }
+    };
     let mut changelog = repo.clone();
     changelog.push(opts.changelog);
-    update_changelog(changelog, &text, opts.in_place)
+    let destination = match opts.out.as_ref() {
+        Some(path) => Destination::File(path),
+        None if opts.in_place => Destination::InPlace,
+        None => Destination::Stdout,
+    };
+    update_changelog(
+        changelog,
+        &text,
+        &opts.anchor,
+        &settings.heading,
+        destination,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scratch::{commit, create_repo, git};
+    use tempfile::tempdir;
+
+    fn changelog_body(text: &str) -> &str {
+        let pos = text.find('\n').unwrap();
+        &text[pos + 1..]
+    }
+
+    #[test]
+    fn changelog_skips_excluded_paths() {
+        let repo = create_repo(&[]);
+        fs::create_dir(repo.path().join("docs")).unwrap();
+        fs::write(repo.path().join("docs/guide.md"), "Guide\n").unwrap();
+        git(repo.path(), &["add", "docs"]);
+        commit(repo.path(), "Update guide");
+        fs::write(repo.path().join("main.c"), "int main;\n").unwrap();
+        fs::write(repo.path().join("docs/guide.md"), "Main\n").unwrap();
+        git(repo.path(), &["add", "main.c", "docs"]);
+        commit(repo.path(), "Add main");
+        let git = Git::new(repo.path());
+        let settings = ChangelogSettings {
+            exclude_paths: vec!["docs".to_string()],
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        assert!(text.contains("Add main"));
+        assert!(!text.contains("Update guide"));
+    }
+
+    #[test]
+    fn previous_tag_skips_new_tag() {
+        let repo = create_repo(&["Add widget"]);
+        let options = git::Options::default();
+        git(
+            repo.path(),
+            &["tag", "-a", "-m", "Release v0.2.0", "v0.2.0"],
+        );
+        let latest =
+            git::latest_tag(repo.path(), &options, true, None).unwrap();
+        assert_eq!(latest, "v0.2.0");
+        let previous =
+            git::previous_tag(repo.path(), &options, &latest, true, None)
+                .unwrap();
+        assert_eq!(previous.as_deref(), Some("v0.1.0"));
+        let previous =
+            git::previous_tag(repo.path(), &options, "v0.1.0", true, None)
+                .unwrap();
+        assert_eq!(previous, None);
+        commit(repo.path(), "Update docs");
+        git(repo.path(), &["tag", "v0.3.0"]);
+        let previous = git::previous_tag(
+            repo.path(),
+            &options,
+            "v0.3.0",
+            false,
+            Some("v0.1*"),
+        )
+        .unwrap();
+        assert_eq!(previous.as_deref(), Some("v0.1.0"));
+        assert!(
+            git::previous_tag(repo.path(), &options, "v9.9.9", true, None)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn auto_tag_starts_at_latest_tag_if_new_tag_is_missing() {
+        let repo = create_repo(&["Add widget"]);
+        let news = "# News\n\n## [v0.1.0] - 2020-01-01\n";
+        fs::write(repo.path().join("NEWS.md"), news).unwrap();
+        let out = repo.path().join("NEWS.new.md");
+        let opts = NevezOptions::from_iter_safe(&[
+            "nevez".as_ref(),
+            "--auto-tag".as_ref(),
+            "-o".as_ref(),
+            out.as_os_str(),
+            "v0.2.0".as_ref(),
+            repo.path().as_os_str(),
+        ])
+        .unwrap();
+        run(opts).unwrap();
+        let text = fs::read_to_string(&out).unwrap();
+        assert!(text.contains("Add widget"));
+        assert!(!text.contains("Initial commit"));
+    }
+
+    #[test]
+    fn changelog_buckets_commits() {
+        let repo = create_repo(&[
+            "Fix crash on empty input",
+            "Update documentation",
+            "Add frobnicator support",
+            "Bump version to 0.2.0",
+            "Squash typo",
+        ]);
+        let git = Git::new(repo.path());
+        let text = generate_changelog(
+            &git,
+            Some("v0.1.0"),
+            "v0.2.0",
+            &Default::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(text.starts_with("## [v0.2.0] - "));
+        assert_eq!(
+            changelog_body(&text),
+            "### Added\n\n- Add frobnicator support\n\n\
+             ### Changed\n\n- Update documentation\n\n\
+             ### Fixed\n\n- Fix crash on empty input\n\n"
+        );
+    }
+
+    #[test]
+    fn changelog_skips_empty_sections() {
+        let repo = create_repo(&["New widget", "Added gadget"]);
+        let git = Git::new(repo.path());
+        let text = generate_changelog(
+            &git,
+            Some("v0.1.0"),
+            "v0.2.0",
+            &Default::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            changelog_body(&text),
+            "### Added\n\n- Added gadget\n- New widget\n\n"
+        );
+    }
+
+    #[test]
+    fn changelog_uses_template() {
+        let repo = create_repo(&["Add widget", "Fix gadget"]);
+        let git = Git::new(repo.path());
+        let template = "{{tag}}:{{#each added}} +{{this}}{{/each}}\
+                        {{#each fixed}} !{{this}}{{/each}}";
+        let settings = ChangelogSettings {
+            template: Some(template.to_string()),
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        assert_eq!(text, "v0.2.0: +Add widget !Fix gadget");
+    }
+
+    #[test]
+    fn changelog_keeps_bumps_as_changes() {
+        let repo = create_repo(&["Bump version to 0.2.0", "Update docs"]);
+        let git = Git::new(repo.path());
+        let settings = ChangelogSettings {
+            keep_bumps: true,
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            changelog_body(&text),
+            "### Changed\n\n- Bump version to 0.2.0\n- Update docs\n\n"
+        );
+    }
+
+    #[test]
+    fn update_changelog_inserts_after_anchor() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("NEWS.md");
+        let section = "## [v0.2.0] - 2020-02-01\n\n";
+        let anchor = "<!-- nevez:insert -->";
+        let heading = HeadingFormat::default();
+        fs::write(
+            &path,
+            "# News\n\n<!-- nevez:insert -->\n\n## [v0.1.0] - 2020-01-01\n",
+        )
+        .unwrap();
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::InPlace,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# News\n\n<!-- nevez:insert -->\n\n## [v0.2.0] - 2020-02-01\n\n\
+             ## [v0.1.0] - 2020-01-01\n"
+        );
+
+        fs::write(&path, "# News\n\n## [v0.1.0] - 2020-01-01\n").unwrap();
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::InPlace,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# News\n\n## [v0.2.0] - 2020-02-01\n\n## [v0.1.0] - 2020-01-01\n"
+        );
+    }
+
+    #[test]
+    fn update_changelog_requires_changelog() {
+        let dir = tempdir().unwrap();
+        let e = update_changelog(
+            dir.path().join("NEWS.md"),
+            "## [v0.2.0] - 2020-02-01\n\n",
+            "<!-- nevez:insert -->",
+            &HeadingFormat::default(),
+            Destination::Stdout,
+        )
+        .unwrap_err();
+        assert_eq!(crate::exit_code(&e), 2);
+    }
+
+    #[test]
+    fn update_changelog_preserves_line_endings() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("NEWS.md");
+        let section = "## [v0.2.0] - 2020-02-01\n\n";
+        let anchor = "<!-- nevez:insert -->";
+        let heading = HeadingFormat::default();
+        fs::write(&path, "# News\r\n\r\n## [v0.1.0] - 2020-01-01\r\n").unwrap();
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::InPlace,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# News\r\n\r\n## [v0.2.0] - 2020-02-01\r\n\r\n\
+             ## [v0.1.0] - 2020-01-01\r\n"
+        );
+
+        fs::write(&path, "# News\n\n## [v0.1.0] - 2020-01-01").unwrap();
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::InPlace,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# News\n\n## [v0.2.0] - 2020-02-01\n\n## [v0.1.0] - 2020-01-01"
+        );
+    }
+
+    #[test]
+    fn update_changelog_writes_to_other_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("NEWS.md");
+        let out = dir.path().join("NEWS.new.md");
+        let section = "## [v0.2.0] - 2020-02-01\n\n";
+        let anchor = "<!-- nevez:insert -->";
+        let heading = HeadingFormat::default();
+        let content = "# News\n\n## [v0.1.0] - 2020-01-01\n";
+        fs::write(&path, content).unwrap();
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::File(&out),
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), content);
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "# News\n\n## [v0.2.0] - 2020-02-01\n\n## [v0.1.0] - 2020-01-01\n"
+        );
+    }
+
+    #[test]
+    fn update_changelog_uses_heading_format() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("NEWS.md");
+        let heading = HeadingFormat::new("## {version} ({date})").unwrap();
+        let section = "## v0.2.0 (2020-02-01)\n\n";
+        fs::write(&path, "# News\n\n## v0.1.0 (2020-01-01)\n").unwrap();
+        let anchor = "<!-- nevez:insert -->";
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::InPlace,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# News\n\n## v0.2.0 (2020-02-01)\n\n## v0.1.0 (2020-01-01)\n"
+        );
+    }
+
+    #[test]
+    fn update_changelog_keeps_unreleased_first() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("NEWS.md");
+        let section = "## [v0.2.0] - 2020-02-01\n\n";
+        let anchor = "<!-- nevez:insert -->";
+        let heading = HeadingFormat::default();
+        fs::write(
+            &path,
+            "# News\n\n## [Unreleased]\n\n## [v0.1.0] - 2020-01-01\n",
+        )
+        .unwrap();
+        update_changelog(
+            &path,
+            section,
+            anchor,
+            &heading,
+            Destination::InPlace,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "# News\n\n## [Unreleased]\n\n## [v0.2.0] - 2020-02-01\n\n\
+             ## [v0.1.0] - 2020-01-01\n"
+        );
+    }
+
+    #[test]
+    fn parse_handles_awkward_authors() {
+        let parser = CommitLogParser::new();
+        let parse = |name: &str, email: &str| {
+            let record = format!(
+                "\n0123456789abcdef\0{}\0{}\0{}\0Add widget\n",
+                name, email, "2020-01-01T00:00:00+00:00"
+            );
+            let commit = parser.parse(&record).unwrap();
+            assert_eq!(commit.message, "Add widget");
+            (commit.author.name, commit.author.email)
+        };
+        assert_eq!(
+            parse("Jane <JD> Doe", "jane@example.com"),
+            ("Jane <JD> Doe".to_string(), "jane@example.com".to_string())
+        );
+        assert_eq!(
+            parse("Jane Doe \t", " jane@example.com "),
+            ("Jane Doe".to_string(), "jane@example.com".to_string())
+        );
+        assert_eq!(
+            parse("Jane Doe", ""),
+            ("Jane Doe".to_string(), "".to_string())
+        );
+        assert!(parser.parse("0123456789abcdef\0Jane Doe").is_none());
+    }
+
+    fn commit_with(message: &str) -> Commit {
+        Commit {
+            id: "0123456789abcdef".to_string(),
+            author: Author {
+                name: "Jane Doe".to_string(),
+                email: "jane@example.com".to_string(),
+            },
+            date: DateTime::parse_from_rfc3339("2020-01-01T00:00:00Z").unwrap(),
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    fn classify_puts_each_commit_in_one_category() {
+        let commits: Vec<Commit> = [
+            "Add widget",
+            "Fix gadget",
+            "Add fix for gadget",
+            "Bump version to 0.2.0",
+            "Update docs",
+            "",
+        ]
+        .iter()
+        .map(|m| commit_with(m))
+        .collect();
+        let classifier = CommitClassifier::new().unwrap();
+        let classified = classifier.classify(&commits);
+        let messages = |commits: &[&Commit]| -> Vec<String> {
+            commits.iter().map(|c| c.message.clone()).collect()
+        };
+        assert_eq!(
+            messages(&classified.additions),
+            vec!["Add widget", "Add fix for gadget"]
+        );
+        assert_eq!(messages(&classified.fixes), vec!["Fix gadget"]);
+        assert_eq!(messages(&classified.changes), vec!["Update docs", ""]);
+        assert_eq!(
+            messages(&classified.ignored),
+            vec!["Bump version to 0.2.0"]
+        );
+    }
+
+    #[test]
+    fn classify_finds_deprecations_removals_and_security_fixes() {
+        let commits: Vec<Commit> = [
+            "Deprecate --foo option",
+            "Remove legacy parser",
+            "Security: escape user input",
+            "Fix CVE-2020-1234 in parser",
+            "Add widget",
+        ]
+        .iter()
+        .map(|m| commit_with(m))
+        .collect();
+        let classifier = CommitClassifier::new().unwrap();
+        let classified = classifier.classify(&commits);
+        assert_eq!(classified.deprecations.len(), 1);
+        assert_eq!(classified.removals.len(), 1);
+        assert_eq!(classified.security.len(), 2);
+        assert_eq!(classified.additions.len(), 1);
+        assert!(classified.fixes.is_empty());
+    }
+
+    #[test]
+    fn changelog_catch_all_lists_commits_without_subject() {
+        let repo = create_repo(&["Update docs"]);
+        git(
+            repo.path(),
+            &[
+                "commit",
+                "--allow-empty",
+                "--allow-empty-message",
+                "-q",
+                "-m",
+                "",
+            ],
+        );
+        let git = Git::new(repo.path());
+        let text = generate_changelog(
+            &git,
+            Some("v0.1.0"),
+            "v0.2.0",
+            &Default::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(changelog_body(&text), "### Changed\n\n- Update docs\n\n");
+        let settings = ChangelogSettings {
+            catch_all: true,
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        let body = changelog_body(&text);
+        assert!(body.starts_with("### Changed\n\n- "));
+        assert!(body.contains(" (no subject)\n"));
+        assert!(body.contains("- Update docs\n"));
+    }
+
+    #[test]
+    fn count_changes_summarizes_categories() {
+        let repo = create_repo(&["Add widget", "Fix gadget", "Update docs"]);
+        let git = Git::new(repo.path());
+        let summary =
+            count_changes(&git, Some("v0.1.0"), &Default::default()).unwrap();
+        assert_eq!(
+            summary,
+            "added=1 changed=1 deprecated=0 removed=0 fixed=1 security=0 \
+             total=3"
+        );
+    }
+
+    #[test]
+    fn changelog_counts_in_header() {
+        let repo = create_repo(&["Add widget", "Add gadget", "Fix gadget"]);
+        let git = Git::new(repo.path());
+        let settings = ChangelogSettings {
+            counts: true,
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        let header = text.lines().next().unwrap();
+        assert!(header.ends_with(" (2 added, 0 changed, 1 fixed)"));
+    }
+
+    #[test]
+    fn changelog_includes_merges() {
+        let repo = create_repo(&[]);
+        git(repo.path(), &["checkout", "-q", "-b", "topic"]);
+        commit(repo.path(), "Fix gadget");
+        git(repo.path(), &["checkout", "-q", "-"]);
+        commit(repo.path(), "Update docs");
+        git(
+            repo.path(),
+            &["merge", "-q", "--no-ff", "-m", "Merge topic", "topic"],
+        );
+        let git = Git::new(repo.path());
+        let text = generate_changelog(
+            &git,
+            Some("v0.1.0"),
+            "v0.2.0",
+            &Default::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            changelog_body(&text),
+            "### Changed\n\n- Update docs\n\n### Fixed\n\n- Fix gadget\n\n"
+        );
+        let settings = ChangelogSettings {
+            include_merges: true,
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        assert_eq!(
+            changelog_body(&text),
+            "### Changed\n\n- Merge topic\n- Update docs\n\n\
+             ### Fixed\n\n- Fix gadget\n\n"
+        );
+    }
+
+    #[test]
+    fn changelog_follows_first_parent() {
+        let repo = create_repo(&[]);
+        git(repo.path(), &["checkout", "-q", "-b", "topic"]);
+        commit(repo.path(), "Fix gadget");
+        git(repo.path(), &["checkout", "-q", "-"]);
+        git(
+            repo.path(),
+            &["merge", "-q", "--no-ff", "-m", "Merge topic", "topic"],
+        );
+        let git = Git::new(repo.path());
+        let settings = ChangelogSettings {
+            include_merges: true,
+            first_parent: true,
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        assert_eq!(changelog_body(&text), "### Changed\n\n- Merge topic\n\n");
+    }
+
+    #[test]
+    fn since_date_must_be_valid() {
+        let parse = |date: &str| {
+            NevezOptions::from_iter_safe(&[
+                "nevez",
+                "--since-date",
+                date,
+                "v1.0.0",
+            ])
+            .map(|opts| opts.since_date)
+        };
+        assert_eq!(
+            parse("2020-1-2").unwrap(),
+            NaiveDate::from_ymd_opt(2020, 1, 2)
+        );
+        assert!(parse("not-a-date").is_err());
+        assert!(parse("2020-13-01").is_err());
+        assert!(parse("yesterday").is_err());
+    }
+
+    #[test]
+    fn heading_format_must_generate_headings() {
+        let parse = |format: &str| {
+            NevezOptions::from_iter_safe(&[
+                "nevez",
+                "--heading-format",
+                format,
+                "v1.0.0",
+            ])
+        };
+        assert!(parse("paren").is_ok());
+        assert!(parse("## {version}").is_ok());
+        assert!(parse("rst").is_err());
+        assert!(parse(r"^## (\S+)$").is_err());
+    }
+
+    #[test]
+    fn out_conflicts_with_in_place() {
+        let parse = |args: &[&str]| NevezOptions::from_iter_safe(args);
+        let opts = parse(&["nevez", "-o", "out.md", "v1.0.0"]).unwrap();
+        assert_eq!(opts.out, Some(PathBuf::from("out.md")));
+        assert!(parse(&["nevez", "-i", "-o", "out.md", "v1.0.0"]).is_err());
+    }
+
+    #[test]
+    fn auto_tag_makes_new_tag_optional() {
+        let dir = tempdir().unwrap();
+        let repo = dir.path().to_str().unwrap();
+        let parse = |args: &[&str]| NevezOptions::from_iter_safe(args);
+        let args = |opts: &NevezOptions| {
+            (opts.new_tag.clone(), opts.repository_dir.clone())
+        };
+        assert!(parse(&["nevez"]).is_err());
+        let opts = parse(&["nevez", "--auto-tag"]).unwrap();
+        assert_eq!(args(&opts), (None, None));
+        let opts = parse(&["nevez", "--auto-tag", repo]).unwrap();
+        assert_eq!(args(&opts), (Some(repo.to_string()), None));
+        let opts = parse(&["nevez", "--auto-tag", "-C", repo]).unwrap();
+        assert_eq!(args(&opts), (None, Some(dir.path().to_path_buf())));
+        assert!(parse(&["nevez", "-C", repo, "1.1.0", repo]).is_err());
+    }
+
+    #[test]
+    fn changelog_stops_at_new_tag() {
+        let repo = create_repo(&["Add widget"]);
+        git(repo.path(), &["tag", "v0.2.0"]);
+        commit(repo.path(), "Fix late bug");
+        let git = Git::new(repo.path());
+        let settings = ChangelogSettings {
+            until: Some("v0.2.0".to_string()),
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap()
+                .unwrap();
+        assert_eq!(changelog_body(&text), "### Added\n\n- Add widget\n\n");
+    }
+
+    #[test]
+    fn changelog_since_date_without_tag() {
+        let repo = create_repo(&["Update docs"]);
+        let git = Git::new(repo.path());
+        let settings = ChangelogSettings {
+            since_date: Some(parse_date("2000-01-01").unwrap()),
+            ..Default::default()
+        };
+        let text = generate_changelog(&git, None, "v0.2.0", &settings)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            changelog_body(&text),
+            "### Changed\n\n- Initial commit\n- Update docs\n\n"
+        );
+        let settings = ChangelogSettings {
+            since_date: Some(parse_date("2090-01-01").unwrap()),
+            ..Default::default()
+        };
+        let text =
+            generate_changelog(&git, Some("v0.1.0"), "v0.2.0", &settings)
+                .unwrap();
+        assert!(text.is_none());
+    }
 }
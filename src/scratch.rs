@@ -0,0 +1,56 @@
+//
+// This file is part of elb-dev-tools-ng
+//
+// Copyright (C) 2020 Eric Le Bihan <eric.le.bihan.dev@free.fr>
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+//
+
+//! Scratch git repositories for tests
+
+use std::path::Path;
+use std::process::Command;
+use tempfile::{tempdir, TempDir};
+
+/// Run git with `args` in `dir`, as Jane Doe
+pub fn git(dir: &Path, args: &[&str]) {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "commit.gpgsign=false", "-c", "tag.gpgsign=false"])
+        .args(args)
+        .env("GIT_AUTHOR_NAME", "Jane Doe")
+        .env("GIT_AUTHOR_EMAIL", "jane@example.com")
+        .env("GIT_COMMITTER_NAME", "Jane Doe")
+        .env("GIT_COMMITTER_EMAIL", "jane@example.com")
+        .output()
+        .expect("failed to run git");
+    assert!(output.status.success(), "git {:?} failed", args);
+}
+
+/// Commit with `message`, changing nothing
+pub fn commit(dir: &Path, message: &str) {
+    git(dir, &["commit", "--allow-empty", "-q", "-m", message]);
+}
+
+/// Commit with `message` as `author`, changing nothing
+pub fn commit_as(dir: &Path, author: &str, message: &str) {
+    let author = format!("--author={}", author);
+    git(
+        dir,
+        &["commit", "--allow-empty", "-q", &author, "-m", message],
+    );
+}
+
+/// Create a repository with an initial commit tagged v0.1.0, followed by a
+/// commit per message
+pub fn create_repo(messages: &[&str]) -> TempDir {
+    let dir = tempdir().unwrap();
+    git(dir.path(), &["init", "-q"]);
+    commit(dir.path(), "Initial commit");
+    git(dir.path(), &["tag", "-a", "-m", "Release v0.1.0", "v0.1.0"]);
+    for message in messages {
+        commit(dir.path(), message);
+    }
+    dir
+}
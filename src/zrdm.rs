@@ -6,72 +6,581 @@
 // SPDX-License-Identifier: MIT OR Apache-2.0
 //
 
+//! Display README from tarball
+
+use crate::{run_command_or, wrap_text, ErrorKind};
 use anyhow::{anyhow, Result};
-use flate2::read::GzDecoder;
+use encoding_rs::{Encoding, UTF_8};
+use flate2::read::MultiGzDecoder;
+use std::collections::BTreeSet;
+use std::env;
 use std::fs::{self, File};
-use std::io;
-use std::path::{Path, PathBuf};
+use std::io::{self, BufRead, BufReader, Cursor, IsTerminal, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
 use structopt::StructOpt;
 use tar::Archive;
-use tempfile::tempdir;
 
-fn is_readme_filename(path: &Path) -> bool {
-    path.to_str().map_or(false, |s| {
-        let filenames = &["readme", "readme.md", "readme.txt"];
-        filenames.iter().any(|f| f.eq_ignore_ascii_case(s))
-    })
+/// Documentation filenames, by decreasing order of preference
+const DOC_FILENAMES: &[&[&str]] = &[
+    &["readme"],
+    &["readme.md", "readme.txt"],
+    &["changelog", "changelog.md"],
+    &["news", "news.md"],
+];
+
+/// Number of entries of `DOC_FILENAMES` matching a README
+const README_PRIORITIES: usize = 2;
+
+/// Return the priority of a documentation file, lower being better
+fn doc_file_priority(path: &Path, fallback: bool) -> Option<usize> {
+    let name = path.to_str()?;
+    let count = if fallback {
+        DOC_FILENAMES.len()
+    } else {
+        README_PRIORITIES
+    };
+    DOC_FILENAMES
+        .iter()
+        .take(count)
+        .position(|names| names.iter().any(|n| n.eq_ignore_ascii_case(name)))
+}
+
+/// Kind of archive, as detected from its first bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ArchiveKind {
+    Gzip,
+    Xz,
+    Zstd,
+    Zip,
+    Tar,
+}
+
+impl ArchiveKind {
+    /// Detect the kind of archive from its magic bytes
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            ArchiveKind::Gzip
+        } else if magic.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]) {
+            ArchiveKind::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            ArchiveKind::Zstd
+        } else if magic.starts_with(&[0x50, 0x4b]) {
+            ArchiveKind::Zip
+        } else {
+            ArchiveKind::Tar
+        }
+    }
+
+    /// Return the name of the kind of archive
+    fn name(self) -> &'static str {
+        match self {
+            ArchiveKind::Gzip => "gzip",
+            ArchiveKind::Xz => "xz",
+            ArchiveKind::Zstd => "zstd",
+            ArchiveKind::Zip => "zip",
+            ArchiveKind::Tar => "tar",
+        }
+    }
+}
+
+/// Maximum depth of archives explored by `open_nested_archive`
+const MAX_NESTING: usize = 4;
+
+/// Read an archive, selecting the decoder from its content
+fn read_archive<'a, R: Read + 'a>(
+    reader: R,
+) -> Result<Archive<Box<dyn Read + 'a>>> {
+    let mut reader = BufReader::new(reader);
+    let kind = ArchiveKind::detect(reader.fill_buf()?);
+    let reader: Box<dyn Read + 'a> = match kind {
+        ArchiveKind::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        ArchiveKind::Tar => Box::new(reader),
+        _ => {
+            return Err(ErrorKind::Input.error(format!(
+                "Unsupported archive format ({})",
+                kind.name()
+            )))
+        }
+    };
+    Ok(Archive::new(reader))
+}
+
+/// Detect the kind of the archive at `path`
+fn detect_archive_kind<P: AsRef<Path>>(path: P) -> Result<ArchiveKind> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(ArchiveKind::detect(reader.fill_buf()?))
+}
+
+/// Open an archive, selecting the decoder from its content
+fn open_archive<P: AsRef<Path>>(path: P) -> Result<Archive<Box<dyn Read>>> {
+    read_archive(File::open(path)?)
+}
+
+/// Check if a file name looks like the one of a tarball
+fn is_tarball_name(path: &Path) -> bool {
+    let name = match path.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name.to_ascii_lowercase(),
+        None => return false,
+    };
+    [".tar", ".tar.gz", ".tgz"]
+        .iter()
+        .any(|ext| name.ends_with(ext))
+}
+
+/// Return the content of the tarball `archive` is only made of, if any
+fn single_nested_archive<R: Read>(
+    archive: &mut Archive<R>,
+) -> Result<Option<Vec<u8>>> {
+    let mut nested = None;
+    for mut entry in archive.entries()?.filter_map(|entry| entry.ok()) {
+        if entry.header().entry_type().is_dir() {
+            continue;
+        }
+        let is_tarball = entry.path().is_ok_and(|path| is_tarball_name(&path));
+        if nested.is_some() || !is_tarball {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .map_err(|e| anyhow!("Failed to read {}", e))?;
+        nested = Some(data);
+    }
+    Ok(nested)
+}
+
+/// Open an archive, descending into the tarball it is only made of, if any
+fn open_nested_archive<P: AsRef<Path>>(
+    path: P,
+) -> Result<Archive<Box<dyn Read>>> {
+    let mut data: Option<Vec<u8>> = None;
+    for _ in 0..MAX_NESTING {
+        let nested = match data.as_ref() {
+            Some(data) => {
+                single_nested_archive(&mut read_archive(data.as_slice())?)?
+            }
+            None => single_nested_archive(&mut open_archive(&path)?)?,
+        };
+        match nested {
+            Some(nested) => data = Some(nested),
+            None => break,
+        }
+    }
+    match data {
+        Some(data) => read_archive(Cursor::new(data)),
+        None => open_archive(path),
+    }
+}
+
+/// Remove the `count` leading components of `path`, like tar does
+fn strip_components(path: &Path, count: usize) -> PathBuf {
+    path.components().skip(count).collect()
+}
+
+/// Extract all documentation files of `archive` into `dir`
+///
+/// The `strip` leading components of the paths of the entries are removed.
+fn extract_docs<R: Read>(
+    archive: &mut Archive<R>,
+    dir: &Path,
+    strip: usize,
+) -> Result<usize> {
+    let mut count = 0;
+    for mut entry in archive.entries()?.filter_map(|entry| entry.ok()) {
+        let path = match entry.path() {
+            Ok(path) => strip_components(&path, strip),
+            Err(_) => continue,
+        };
+        if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+            continue;
+        }
+        let is_doc = path
+            .file_name()
+            .and_then(|name| doc_file_priority(Path::new(name), true))
+            .is_some();
+        if !is_doc {
+            continue;
+        }
+        let path = dir.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry
+            .unpack(&path)
+            .map_err(|e| anyhow!("Failed to unpack {}", e))?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+/// Byte order mark of UTF-8 text
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Strip a UTF-8 BOM from `data`, converting UTF-16 text if `transcode` is set
+fn decode_text(data: Vec<u8>, transcode: bool) -> Vec<u8> {
+    if transcode {
+        if let Some((encoding, bom_len)) = Encoding::for_bom(&data) {
+            if encoding != UTF_8 {
+                let (text, _) =
+                    encoding.decode_without_bom_handling(&data[bom_len..]);
+                return text.into_owned().into_bytes();
+            }
+        }
+    }
+    if data.starts_with(UTF8_BOM) {
+        data[UTF8_BOM.len()..].to_vec()
+    } else {
+        data
+    }
+}
+
+/// Report the number of archive entries scanned so far on stderr
+struct Progress {
+    enabled: bool,
+    count: usize,
+}
+
+impl Progress {
+    /// Create a progress indicator, only enabled if stderr is a terminal
+    fn new(enabled: bool) -> Self {
+        Progress {
+            enabled: enabled && io::stderr().is_terminal(),
+            count: 0,
+        }
+    }
+
+    /// Account for a new scanned entry
+    fn tick(&mut self) {
+        self.count += 1;
+        if self.enabled && self.count.is_multiple_of(100) {
+            eprint!("\r{} entries scanned", self.count);
+        }
+    }
+
+    /// Clear the indicator
+    fn finish(&self) {
+        if self.enabled && self.count >= 100 {
+            eprint!("\r\x1b[K");
+        }
+    }
+}
+
+/// Return the number of entries of `archive` and its top-level names
+fn archive_summary<R: Read>(
+    archive: &mut Archive<R>,
+) -> Result<(usize, BTreeSet<PathBuf>)> {
+    let mut count = 0;
+    let mut top_level = BTreeSet::new();
+    for entry in archive.entries()?.filter_map(|entry| entry.ok()) {
+        count += 1;
+        if let Ok(path) = entry.path() {
+            if let Some(Component::Normal(name)) = path.components().next() {
+                top_level.insert(PathBuf::from(name));
+            }
+        }
+    }
+    Ok((count, top_level))
+}
+
+/// Guess the width of the terminal
+fn terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .or_else(|| {
+            let mut cmd = Command::new("tput");
+            cmd.arg("cols");
+            run_command_or(&mut cmd, "tput failed")
+                .ok()
+                .and_then(|s| s.parse().ok())
+        })
+        .unwrap_or(80)
+}
+
+/// Write a banner naming the archive and the file being displayed
+fn write_header<W: Write>(
+    out: &mut W,
+    archive: &Path,
+    path: &Path,
+) -> Result<()> {
+    writeln!(out, "==> {}: {} <==", archive.display(), path.display())
+        .map_err(|e| anyhow!("Failed to output ({})", e))
 }
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "zrdm", about = "Display README from tarball")]
-struct ZrdmOpts {
+pub struct ZrdmOpts {
+    #[structopt(
+        long = "no-fallback",
+        help = "Do not fall back to CHANGELOG or NEWS"
+    )]
+    no_fallback: bool,
+
+    #[structopt(
+        short = "x",
+        long = "extract-docs",
+        help = "Extract all documentation files into directory",
+        value_name = "DIR",
+        parse(from_os_str)
+    )]
+    extract_docs: Option<PathBuf>,
+
+    #[structopt(
+        short = "w",
+        long = "wrap",
+        help = "Wrap plain text to terminal or given width",
+        value_name = "WIDTH",
+        require_equals = true
+    )]
+    wrap: Option<Option<usize>>,
+
+    #[structopt(
+        short = "H",
+        long = "header",
+        help = "Print archive and file names before content"
+    )]
+    header: bool,
+
+    #[structopt(
+        long = "transcode",
+        help = "Convert UTF-16 text to UTF-8",
+        conflicts_with = "raw"
+    )]
+    transcode: bool,
+
+    #[structopt(
+        long = "raw",
+        help = "Output file content without any processing",
+        conflicts_with = "wrap"
+    )]
+    raw: bool,
+
+    #[structopt(
+        long = "progress",
+        help = "Show number of entries scanned on terminal"
+    )]
+    progress: bool,
+
+    #[structopt(
+        long = "nested",
+        help = "Look into the tarball the archive is only made of"
+    )]
+    nested: bool,
+
+    #[structopt(
+        long = "info",
+        help = "Print archive format, entry count and top-level names"
+    )]
+    info: bool,
+
+    #[structopt(
+        long = "strip-components",
+        help = "Strip leading components from entry names",
+        value_name = "N",
+        default_value = "1"
+    )]
+    strip_components: usize,
+
     #[structopt(help = "Archive to explore")]
     tarball: PathBuf,
 }
 
-fn main() -> Result<()> {
-    let opts = ZrdmOpts::from_args();
-    let file = File::open(&opts.tarball)?;
-    let mut archive = Archive::new(GzDecoder::new(file));
-    let mut candidates = archive
-        .entries()?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| {
-            if let Ok(path) = entry.path() {
-                let path: PathBuf = path.components().skip(1).collect();
-                return is_readme_filename(&path);
+/// Run zrdm with options `opts`
+pub fn run(opts: ZrdmOpts) -> Result<()> {
+    let fallback = !opts.no_fallback;
+    let mut archive = if opts.nested {
+        open_nested_archive(&opts.tarball)?
+    } else {
+        open_archive(&opts.tarball)?
+    };
+
+    if let Some(dir) = opts.extract_docs {
+        let count = extract_docs(&mut archive, &dir, opts.strip_components)?;
+        println!("{} file(s) written to {}", count, dir.display());
+        return Ok(());
+    }
+
+    if opts.info {
+        let kind = detect_archive_kind(&opts.tarball)?;
+        let (count, top_level) = archive_summary(&mut archive)?;
+        println!("format: {}", kind.name());
+        println!("entries: {}", count);
+        for name in top_level {
+            println!("top-level: {}", name.display());
+        }
+        return Ok(());
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    let mut found: Option<(usize, PathBuf, Vec<u8>)> = None;
+    let mut progress = Progress::new(opts.progress);
+
+    for mut entry in archive.entries()?.filter_map(|entry| entry.ok()) {
+        progress.tick();
+        let entry_path = match entry.path() {
+            Ok(path) => path.into_owned(),
+            Err(_) => continue,
+        };
+        let path = strip_components(&entry_path, opts.strip_components);
+        let priority = match doc_file_priority(&path, fallback) {
+            Some(priority) => priority,
+            None => continue,
+        };
+        if found.as_ref().is_some_and(|(best, _, _)| *best <= priority) {
+            continue;
+        }
+        if priority == 0 && opts.wrap.is_none() && !opts.transcode {
+            progress.finish();
+            if opts.header {
+                write_header(&mut stdout, &opts.tarball, &entry_path)?;
             }
-            false
-        });
-
-    let tmpdir = tempdir()?;
-
-    let path = candidates
-        .next()
-        .ok_or(anyhow!("No README found"))
-        .and_then(|mut entry| {
-            if let Ok(path) = entry.path() {
-                let path: PathBuf = path.components().skip(1).collect();
-                let path = tmpdir.path().join(path);
-                entry
-                    .unpack(&path)
-                    .map_err(|e| anyhow!("Failed to unpack {}", e))?;
-                Ok(path)
-            } else {
-                Err(anyhow!("Invalid path"))
+            let mut reader = BufReader::new(&mut entry);
+            if !opts.raw && reader.fill_buf()?.starts_with(UTF8_BOM) {
+                reader.consume(UTF8_BOM.len());
             }
-        })?;
-
-    File::open(&path)
-        .map_err(|e| anyhow!("Failed to open ({})", e))
-        .and_then(|mut f| {
-            let stdout = io::stdout();
-            let mut stdout = stdout.lock();
-            io::copy(&mut f, &mut stdout)
-                .map_err(|e| anyhow!("Failed to output ({})", e))
-        })
-        .and_then(|_| {
-            fs::remove_file(&path)
-                .map_err(|e| anyhow!("Failed to remove file ({})", e))
-        })
+            io::copy(&mut reader, &mut stdout)
+                .map_err(|e| anyhow!("Failed to output ({})", e))?;
+            return Ok(());
+        }
+        let mut data = Vec::new();
+        entry
+            .read_to_end(&mut data)
+            .map_err(|e| anyhow!("Failed to read {}", e))?;
+        found = Some((priority, entry_path, data));
+        if priority == 0 {
+            break;
+        }
+    }
+    progress.finish();
+
+    let (_, entry_path, data) = found.ok_or_else(|| {
+        if fallback {
+            ErrorKind::Validation.error("No README, CHANGELOG or NEWS found")
+        } else {
+            ErrorKind::Validation.error("No README found")
+        }
+    })?;
+
+    let data = if opts.raw {
+        data
+    } else {
+        decode_text(data, opts.transcode)
+    };
+    let data = match opts.wrap {
+        Some(width) => {
+            let width = width.unwrap_or_else(terminal_width);
+            match String::from_utf8(data) {
+                Ok(text) => wrap_text(&text, width).into_bytes(),
+                Err(e) => e.into_bytes(),
+            }
+        }
+        None => data,
+    };
+    if opts.header {
+        write_header(&mut stdout, &opts.tarball, &entry_path)?;
+    }
+    stdout
+        .write_all(&data)
+        .map_err(|e| anyhow!("Failed to output ({})", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::fs;
+    use std::io::Write;
+    use tar::{Builder, Header};
+    use tempfile::tempdir;
+
+    fn append_file(builder: &mut Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn decode_text_handles_bom() {
+        assert_eq!(decode_text(b"\xef\xbb\xbfHi\n".to_vec(), false), b"Hi\n");
+        assert_eq!(decode_text(b"\xff\xfeH\0i\0".to_vec(), true), b"Hi");
+        assert_eq!(decode_text(b"\xfe\xff\0H\0i".to_vec(), true), b"Hi");
+        assert_eq!(decode_text(b"\xff\xfeH\0".to_vec(), false), b"\xff\xfeH\0");
+    }
+
+    #[test]
+    fn open_nested_archive_reads_inner_tarball() {
+        let mut builder = Builder::new(Vec::new());
+        append_file(&mut builder, "pkg-1.0/README", b"Hello\n");
+        let inner = gzip(&builder.into_inner().unwrap());
+        let mut builder = Builder::new(Vec::new());
+        append_file(&mut builder, "pkg-1.0.tar.gz", &inner);
+        let outer = builder.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("release.tar");
+        fs::write(&path, &outer).unwrap();
+        let mut archive = open_nested_archive(&path).unwrap();
+        let output = dir.path().join("docs");
+        assert_eq!(extract_docs(&mut archive, &output, 1).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(output.join("README")).unwrap(),
+            "Hello\n"
+        );
+    }
+
+    #[test]
+    fn open_archive_reads_all_gzip_members() {
+        let mut builder = Builder::new(Vec::new());
+        append_file(&mut builder, "pkg-1.0/main.c", b"int main;\n");
+        append_file(&mut builder, "pkg-1.0/README", b"Hello\n");
+        let tarball = builder.into_inner().unwrap();
+        let (first, second) = tarball.split_at(1024);
+        let mut data = gzip(first);
+        data.extend(gzip(second));
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pkg-1.0.tar.gz");
+        fs::write(&path, &data).unwrap();
+        let mut archive = open_archive(&path).unwrap();
+        let output = dir.path().join("docs");
+        assert_eq!(extract_docs(&mut archive, &output, 1).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(output.join("README")).unwrap(),
+            "Hello\n"
+        );
+    }
+
+    #[test]
+    fn extract_docs_strips_components() {
+        let mut builder = Builder::new(Vec::new());
+        append_file(&mut builder, "pkg-1.0/pkg/README", b"Hello\n");
+        append_file(&mut builder, "pkg-1.0/README", b"Top\n");
+        let tarball = builder.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pkg-1.0.tar");
+        fs::write(&path, &tarball).unwrap();
+        let mut archive = open_archive(&path).unwrap();
+        let output = dir.path().join("docs");
+        assert_eq!(extract_docs(&mut archive, &output, 2).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(output.join("README")).unwrap(),
+            "Hello\n"
+        );
+        assert_eq!(
+            strip_components(Path::new("a/b/c/README"), 1),
+            Path::new("b/c/README")
+        );
+    }
 }